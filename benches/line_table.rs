@@ -0,0 +1,78 @@
+//! memory taken by the line table on large generated scripts, compared with the one
+//! `usize` per code byte the chunk used to store. Run with `cargo bench --bench line_table`.
+#![allow(clippy::print_with_newline)]
+
+use r_lox_bytecode::chunk::{Chunk, LineRun};
+use r_lox_bytecode::{VMConfig, VM};
+use std::mem::size_of;
+use std::time::Instant;
+
+/// a script of about `lines` lines of functions mixing arithmetic, control flow and calls.
+/// The bodies only use locals, each function compiles to its own chunk
+fn script(lines: usize) -> String {
+    let body = [
+        "    a = a * b + c - b;\n",
+        "    if (a > b) a = a - c; else b = b + c;\n",
+        "    c = a ?? b;\n",
+        "    print a;\n",
+    ];
+    let mut source = String::new();
+    for f in 0..lines.div_ceil(1000) {
+        source.push_str(&format!("fun f{}(a, b, c) {{\n", f));
+        for line in 0..998 {
+            source.push_str(body[line % body.len()]);
+        }
+        source.push_str("    return a;\n}\n");
+    }
+    source
+}
+
+fn compile(source: &str) -> Chunk {
+    let mut vm = VM::new(VMConfig::default());
+    vm.compile(source)
+        .expect("benchmark script does not compile")
+}
+
+/// code bytes and line runs of the chunk and every function in it
+fn sizes(chunk: &Chunk) -> (usize, usize) {
+    let mut code = chunk.codes.len();
+    let mut runs = chunk.line_runs().len();
+    for function in chunk.functions() {
+        let (c, r) = sizes(&function.chunk);
+        code += c;
+        runs += r;
+    }
+    (code, runs)
+}
+
+fn main() {
+    print!(
+        "{:>8} {:>10} {:>14} {:>14} {:>8} {:>12}\n",
+        "lines", "code", "old table", "line runs", "saved", "get_line"
+    );
+    for lines in [1_000, 10_000, 100_000] {
+        let chunk = compile(&script(lines));
+        let (code, runs) = sizes(&chunk);
+        let old = code * size_of::<usize>();
+        let new = runs * size_of::<LineRun>();
+        // what a runtime error or the disassembler pays to find a line
+        let start = Instant::now();
+        let mut sum = 0;
+        for function in chunk.functions() {
+            for offset in 0..function.chunk.codes.len() {
+                sum += function.chunk.get_line(offset);
+            }
+        }
+        let per_lookup = start.elapsed() / code as u32;
+        assert!(sum > 0);
+        print!(
+            "{:>8} {:>10} {:>14} {:>14} {:>7.1}x {:>12?}\n",
+            lines,
+            code,
+            old,
+            new,
+            old as f64 / new as f64,
+            per_lookup
+        );
+    }
+}
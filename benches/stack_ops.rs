@@ -0,0 +1,91 @@
+//! run time of scripts dominated by single stack operations: arithmetic, comparisons and
+//! the pushes and pops around them. Run with `cargo bench --bench stack_ops`.
+#![allow(clippy::print_with_newline)]
+
+use r_lox_bytecode::{VMConfig, VM};
+use std::time::{Duration, Instant};
+
+/// a function whose body is `line` repeated `steps` times, called from a recursion that
+/// splits `calls` in halves
+fn repeated(setup: &str, line: &str, steps: usize, calls: usize) -> String {
+    let mut source = format!("fun body(n) {{\n{}", setup);
+    for _ in 0..steps {
+        source.push_str(line);
+    }
+    source.push_str("    return acc;\n}\n");
+    source.push_str(&format!(
+        "fun calls(k) {{
+    if (k < 2) return body(k);
+    return calls(k / 2) + calls(k - k / 2);
+}}
+print calls({});
+",
+        calls
+    ));
+    source
+}
+
+fn scripts() -> Vec<(&'static str, String)> {
+    let ints = "    var acc = 0;\n    var x = n + 3;\n";
+    let floats = "    var acc = 0.5;\n    var x = n + 0.25;\n";
+    vec![
+        (
+            "int arith",
+            repeated(ints, "    acc = (acc + x * 3 - x) % 1000;\n", 100, 3000),
+        ),
+        (
+            "float arith",
+            repeated(floats, "    acc = acc * 0.5 + x / 4 - 1.5;\n", 100, 3000),
+        ),
+        (
+            "compare",
+            repeated(
+                ints,
+                "    acc = acc + (x < acc ? 1 : 2) + (x >= acc ? 3 : 4);\n",
+                100,
+                3000,
+            ),
+        ),
+        (
+            "bitwise",
+            repeated(
+                ints,
+                "    acc = ((acc ^ x) | 5) & 1023 << 1 >> 1;\n",
+                100,
+                3000,
+            ),
+        ),
+        (
+            "equality",
+            repeated(
+                ints,
+                "    acc = acc + (x == acc ? 1 : 0) + (x != 2 ? 1 : 0);\n",
+                100,
+                3000,
+            ),
+        ),
+    ]
+}
+
+/// the fastest of a few runs of the script
+fn time(source: &str) -> Duration {
+    (0..5)
+        .map(|_| {
+            let mut vm = VM::new(VMConfig {
+                out: Box::new(std::io::sink()),
+                ..VMConfig::default()
+            });
+            let start = Instant::now();
+            vm.interpret(source);
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    print!("{:>12} {:>12}\n", "script", "time");
+    for (name, source) in scripts() {
+        print!("{:>12} {:>12?}\n", name, time(&source));
+    }
+}
@@ -0,0 +1,73 @@
+//! run time of local-heavy scripts with the peephole pass alone (`-O1`) and with
+//! superinstructions fused as well (`-O2`). Run with `cargo bench --bench superinstructions`.
+#![allow(clippy::print_with_newline)]
+
+use r_lox_bytecode::{VMConfig, VM};
+use std::time::{Duration, Instant};
+
+/// recursion splitting a range in halves, which spends its time on `lo + 1`, `next < hi`
+/// and calls
+const SUM: &str = "
+fun sum(lo, hi) {
+    var next = lo + 1;
+    if (next < hi) {
+        var mid = (lo + hi) / 2;
+        return sum(lo, mid) + sum(mid, hi);
+    }
+    return lo;
+}
+print sum(0, 200000);
+";
+
+/// a counting body unrolled `steps` times, called from a recursion like `SUM`'s
+fn counting(steps: usize) -> String {
+    let mut source = String::from("fun count(n) {\n    var i = 0;\n    var acc = 0;\n");
+    for _ in 0..steps {
+        source.push_str("    if (i < n) { acc += i; i++; }\n");
+    }
+    source.push_str("    return acc;\n}\n");
+    source.push_str(
+        "fun calls(k) {
+    if (k < 2) return count(100);
+    return calls(k / 2) + calls(k - k / 2);
+}
+print calls(2000);
+",
+    );
+    source
+}
+
+/// the fastest of a few runs of the script compiled at `opt_level`
+fn time(source: &str, opt_level: u8) -> Duration {
+    (0..5)
+        .map(|_| {
+            let mut vm = VM::new(VMConfig {
+                out: Box::new(std::io::sink()),
+                opt_level,
+                ..VMConfig::default()
+            });
+            let start = Instant::now();
+            vm.interpret(source);
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    print!(
+        "{:>10} {:>12} {:>12} {:>8}\n",
+        "script", "-O1", "-O2", "speedup"
+    );
+    for (name, source) in [("sum", SUM.to_string()), ("counting", counting(100))] {
+        let before = time(&source, 1);
+        let after = time(&source, 2);
+        print!(
+            "{:>10} {:>12?} {:>12?} {:>7.2}x\n",
+            name,
+            before,
+            after,
+            before.as_secs_f64() / after.as_secs_f64()
+        );
+    }
+}
@@ -1,10 +1,9 @@
-use crate::chunk::OpCode::{OP_CONSTANT, OP_NEGATE, OP_RETURN};
+use crate::object::{Function, Obj};
 use crate::value::{Value, ValueArray};
-use int_enum::IntEnum;
 
 use int_to_c_enum::TryFromInt;
 #[repr(u8)]
-#[derive(TryFromInt, Debug)]
+#[derive(TryFromInt, Debug, Copy, Clone, PartialEq)]
 pub enum OpCode {
     OP_CONSTANT = 0,
     OP_NIL = 1,
@@ -26,18 +25,148 @@ pub enum OpCode {
     OP_NOT = 12,
     OP_NEGATE = 13,
     OP_PRINT = 14,
+    OP_JUMP = 16,
+    OP_JUMP_IF_FALSE = 17,
+    OP_LOOP = 18,
+    /// `OP_JUMP_TABLE min:i16 count:u8 [offset:u16; count] default:u16`, pops an integer
+    /// and jumps backward from the end of the table by the offset of its entry
+    OP_JUMP_TABLE = 19,
+    OP_DUP = 20,
+    OP_MODULO = 21,
+    OP_JUMP_IF_NOT_NIL = 22,
+    OP_CALL = 23,
+    /// `OP_CLOSURE function:u8 [is_local:u8 index:u16; upvalue_count]`
+    OP_CLOSURE = 24,
+    OP_GET_UPVALUE = 25,
+    OP_SET_UPVALUE = 26,
+    OP_CLOSE_UPVALUE = 27,
+    /// `OP_CALL_KW arg_count:u8 keyword_count:u8 [name:u8; keyword_count]`, the last
+    /// `keyword_count` arguments are passed by the parameter names in the constant pool
+    OP_CALL_KW = 28,
+    OP_GET_INDEX = 29,
+    /// `OP_IMPORT path:u8`, loads the module at the path on first use and pushes it
+    OP_IMPORT = 30,
+    OP_GET_PROPERTY = 31,
+    OP_BIT_AND = 32,
+    OP_BIT_OR = 33,
+    OP_BIT_XOR = 34,
+    OP_BIT_NOT = 35,
+    OP_SHIFT_LEFT = 36,
+    OP_SHIFT_RIGHT = 37,
+    /// pops and prints the repr of a top-level expression statement in the REPL, unless it is nil
+    OP_ECHO = 38,
+    /// `OP_INVOKE name:u8 arg_count:u8`, calls a method of the receiver below the arguments
+    OP_INVOKE = 39,
+    /// `OP_INVOKE_KW name:u8 arg_count:u8 keyword_count:u8 keyword_name:u8...`
+    OP_INVOKE_KW = 40,
+    /// the `_LONG` forms take a 24-bit big-endian index in place of the u8 one, for chunks
+    /// with more than 256 constants and functions with more than 256 locals
+    OP_CONSTANT_LONG = 41,
+    OP_GET_GLOBAL_LONG = 42,
+    OP_DEFINE_GLOBAL_LONG = 43,
+    OP_SET_GLOBAL_LONG = 44,
+    OP_GET_PROPERTY_LONG = 45,
+    OP_CLOSURE_LONG = 46,
+    OP_INVOKE_LONG = 47,
+    /// every name in the keyword list is 24-bit too
+    OP_CALL_KW_LONG = 48,
+    OP_INVOKE_KW_LONG = 49,
+    OP_IMPORT_LONG = 50,
+    OP_GET_LOCAL_LONG = 51,
+    OP_SET_LOCAL_LONG = 52,
+    /// the fused forms of `OP_EQUAL, OP_NOT`, `OP_LESS, OP_NOT` and `OP_GREATER, OP_NOT`,
+    /// which the optimizer substitutes; like the pairs, `>=` and `<=` are true for NaN
+    OP_NOT_EQUAL = 53,
+    OP_GREATER_EQUAL = 54,
+    OP_LESS_EQUAL = 56,
+    /// superinstructions for hot sequences, fused by the optimizer at level 2.
+    /// `OP_ADD_LOCAL_CONST slot:u8 constant:u8` is `OP_GET_LOCAL, OP_CONSTANT, OP_ADD`
+    OP_ADD_LOCAL_CONST = 57,
+    /// `OP_LESS_LOCALS_JUMP a:u8 b:u8 offset:u16` jumps forward unless local `a` is less
+    /// than local `b`, in place of `OP_GET_LOCAL, OP_GET_LOCAL, OP_LESS, OP_JUMP_IF_FALSE`
+    /// and the `OP_POP` on either side of the jump
+    OP_LESS_LOCALS_JUMP = 58,
+    /// `OP_INC_LOCAL slot:u8` is the statement `slot++;` or `slot += 1;`
+    OP_INC_LOCAL = 59,
+    /// `OP_MATCH_LIST length:u8` replaces the value on top with whether it is a list of
+    /// exactly `length` elements, for list patterns
+    OP_MATCH_LIST = 60,
     OP_RETURN = 15,
 }
 
+/// the largest constant index a `_LONG` instruction can hold
+pub const CONSTANT_LONG_MAX: usize = (1 << 24) - 1;
+
+impl OpCode {
+    /// the variant of an instruction with a 24-bit index, if it has one
+    pub fn long_form(self) -> Option<OpCode> {
+        match self {
+            OpCode::OP_CONSTANT => Some(OpCode::OP_CONSTANT_LONG),
+            OpCode::OP_GET_GLOBAL => Some(OpCode::OP_GET_GLOBAL_LONG),
+            OpCode::OP_DEFINE_GLOBAL => Some(OpCode::OP_DEFINE_GLOBAL_LONG),
+            OpCode::OP_SET_GLOBAL => Some(OpCode::OP_SET_GLOBAL_LONG),
+            OpCode::OP_GET_PROPERTY => Some(OpCode::OP_GET_PROPERTY_LONG),
+            OpCode::OP_CLOSURE => Some(OpCode::OP_CLOSURE_LONG),
+            OpCode::OP_INVOKE => Some(OpCode::OP_INVOKE_LONG),
+            OpCode::OP_CALL_KW => Some(OpCode::OP_CALL_KW_LONG),
+            OpCode::OP_INVOKE_KW => Some(OpCode::OP_INVOKE_KW_LONG),
+            OpCode::OP_IMPORT => Some(OpCode::OP_IMPORT_LONG),
+            OpCode::OP_GET_LOCAL => Some(OpCode::OP_GET_LOCAL_LONG),
+            OpCode::OP_SET_LOCAL => Some(OpCode::OP_SET_LOCAL_LONG),
+            _ => None,
+        }
+    }
+
+    /// whether the constant or slot operand of this instruction is 24 bits wide
+    pub fn is_long(self) -> bool {
+        matches!(
+            self,
+            OpCode::OP_CONSTANT_LONG
+                | OpCode::OP_GET_GLOBAL_LONG
+                | OpCode::OP_DEFINE_GLOBAL_LONG
+                | OpCode::OP_SET_GLOBAL_LONG
+                | OpCode::OP_GET_PROPERTY_LONG
+                | OpCode::OP_CLOSURE_LONG
+                | OpCode::OP_INVOKE_LONG
+                | OpCode::OP_CALL_KW_LONG
+                | OpCode::OP_INVOKE_KW_LONG
+                | OpCode::OP_IMPORT_LONG
+                | OpCode::OP_GET_LOCAL_LONG
+                | OpCode::OP_SET_LOCAL_LONG
+        )
+    }
+}
+
+/// read the 24-bit big-endian operand of a `_LONG` instruction
+pub fn read_long(codes: &[u8], offset: usize) -> usize {
+    return (codes[offset] as usize) << 16
+        | (codes[offset + 1] as usize) << 8
+        | codes[offset + 2] as usize;
+}
+
+/// the code bytes from `start` up to the next run's start were compiled from `line`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct LineRun {
+    pub start: u32,
+    pub line: u32,
+}
+
 /// vm instruction, store all instructions
 #[derive(Debug, Clone)]
 pub struct Chunk {
     /// store instructions and operands
     pub codes: Vec<u8>,
-    pub lines: Vec<usize>, // line number,  TODO improve: use run-length encoding
+    /// run-length encoded source lines of `codes`, ordered by start offset
+    pub(crate) lines: Vec<LineRun>,
     pub(crate) constants: ValueArray,
 }
 
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Chunk {
     pub fn new() -> Self {
         Chunk {
@@ -49,8 +178,50 @@ impl Chunk {
 
     /// write opcodes or operands. It’s all raw bytes
     pub fn write_chunk<B: Into<u8>>(&mut self, byte: B, line: usize) {
+        if self
+            .lines
+            .last()
+            .is_none_or(|run| run.line as usize != line)
+        {
+            self.lines.push(LineRun {
+                start: self.codes.len() as u32,
+                line: line as u32,
+            });
+        }
         self.codes.push(byte.into());
-        self.lines.push(line);
+    }
+
+    /// the source line of the code byte at `offset`
+    pub fn get_line(&self, offset: usize) -> usize {
+        let runs = self
+            .lines
+            .partition_point(|run| run.start as usize <= offset);
+        match runs.checked_sub(1) {
+            Some(run) => self.lines[run].line as usize,
+            None => 0,
+        }
+    }
+
+    pub fn line_runs(&self) -> &[LineRun] {
+        &self.lines
+    }
+
+    /// the functions declared directly in this chunk, from its constant pool
+    pub fn functions(&self) -> impl Iterator<Item = &Function> {
+        self.constants
+            .values
+            .iter()
+            .filter_map(|value| match value.as_obj() {
+                Some(Obj::function(function)) => Some(&**function),
+                _ => None,
+            })
+    }
+
+    /// drop the code from `len` on, with its lines
+    pub fn truncate(&mut self, len: usize) {
+        self.codes.truncate(len);
+        let runs = self.lines.partition_point(|run| (run.start as usize) < len);
+        self.lines.truncate(runs);
     }
 
     pub fn count(&self) -> usize {
@@ -64,30 +235,8 @@ impl Chunk {
     }
 }
 
-impl Into<u8> for OpCode {
-    fn into(self) -> u8 {
-        match self {
-            OP_CONSTANT => 0,
-            OpCode::OP_NIL => 1,
-            OpCode::OP_TRUE => 2,
-            OpCode::OP_FALSE => 3,
-            OpCode::OP_POP => 4,
-            OpCode::OP_GET_LOCAL => 222,
-            OpCode::OP_SET_LOCAL => 223,
-            OpCode::OP_GET_GLOBAL => 33,
-            OpCode::OP_SET_GLOBAL => 34,
-            OpCode::OP_DEFINE_GLOBAL => 55,
-            OpCode::OP_EQUAL => 4,
-            OpCode::OP_GREATER => 5,
-            OpCode::OP_LESS => 6,
-            OpCode::OP_ADD => 7,
-            OpCode::OP_SUBTRACT => 8,
-            OpCode::OP_MULTIPLY => 9,
-            OpCode::OP_DIVIDE => 10,
-            OpCode::OP_NOT => 11,
-            OP_NEGATE => 12,
-            OpCode::OP_PRINT => 13,
-            OP_RETURN => 14,
-        }
+impl From<OpCode> for u8 {
+    fn from(op: OpCode) -> Self {
+        op as u8
     }
 }
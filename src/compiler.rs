@@ -1,26 +1,120 @@
 use crate::chunk::OpCode::{
-    OP_ADD, OP_CONSTANT, OP_DEFINE_GLOBAL, OP_DIVIDE, OP_EQUAL, OP_FALSE, OP_GET_GLOBAL,
-    OP_GET_LOCAL, OP_GREATER, OP_LESS, OP_MULTIPLY, OP_NEGATE, OP_NIL, OP_NOT, OP_POP, OP_PRINT,
-    OP_RETURN, OP_SET_GLOBAL, OP_SET_LOCAL, OP_SUBTRACT, OP_TRUE,
+    OP_ADD, OP_BIT_AND, OP_BIT_NOT, OP_BIT_OR, OP_BIT_XOR, OP_CALL, OP_CALL_KW, OP_CALL_KW_LONG,
+    OP_CLOSE_UPVALUE, OP_CLOSURE, OP_CONSTANT, OP_CONSTANT_LONG, OP_DEFINE_GLOBAL, OP_DIVIDE,
+    OP_DUP, OP_ECHO, OP_EQUAL, OP_FALSE, OP_GET_GLOBAL, OP_GET_INDEX, OP_GET_LOCAL,
+    OP_GET_PROPERTY, OP_GET_UPVALUE, OP_GREATER, OP_IMPORT, OP_INVOKE, OP_INVOKE_KW,
+    OP_INVOKE_KW_LONG, OP_JUMP, OP_JUMP_IF_FALSE, OP_JUMP_IF_NOT_NIL, OP_JUMP_TABLE, OP_LESS,
+    OP_LOOP, OP_MATCH_LIST, OP_MODULO, OP_MULTIPLY, OP_NEGATE, OP_NIL, OP_NOT, OP_POP, OP_PRINT,
+    OP_RETURN, OP_SET_GLOBAL, OP_SET_LOCAL, OP_SET_UPVALUE, OP_SHIFT_LEFT, OP_SHIFT_RIGHT,
+    OP_SUBTRACT, OP_TRUE,
 };
-use crate::chunk::{Chunk, OpCode};
+use crate::chunk::{read_long, Chunk, OpCode, CONSTANT_LONG_MAX};
+use crate::compiler::FunctionType::{TYPE_FUNCTION, TYPE_SCRIPT};
 use crate::compiler::Precedence::{
-    PREC_ASSIGNMENT, PREC_COMPARISON, PREC_EQUALITY, PREC_FACTOR, PREC_NONE, PREC_TERM, PREC_UNARY,
+    PREC_AND, PREC_ASSIGNMENT, PREC_BIT_AND, PREC_BIT_OR, PREC_BIT_XOR, PREC_CALL, PREC_COALESCE,
+    PREC_COMPARISON, PREC_CONDITIONAL, PREC_EQUALITY, PREC_FACTOR, PREC_NONE, PREC_OR,
+    PREC_PRIMARY, PREC_SHIFT, PREC_TERM, PREC_UNARY,
 };
+use crate::compiler::ScriptMode::{MODE_ECHO, MODE_EVAL, MODE_RUN};
+#[cfg(feature = "debug_print_code")]
 use crate::debug::disassemble_chunk;
-use crate::object::Obj;
+use crate::fold::fold;
+use crate::object::{Function, Obj};
+use crate::optimize::optimize;
 use crate::scanner::TokenType::{TOKEN_EOF, TOKEN_ERROR, TOKEN_RIGHT_PAREN};
 use crate::scanner::{Scanner, Token, TokenType, TokenType::*};
-use crate::value::Value;
+use crate::value::{values_equal, Value};
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+/// locals per function, slots past 255 are reached through OP_GET_LOCAL_LONG/OP_SET_LOCAL_LONG
+/// and captured with a u16 index
+const LOCALS_MAX: usize = u16::MAX as usize + 1;
+
+/// a dense integer `match` needs at least this many cases to be dispatched through a jump table
+const JUMP_TABLE_MIN_CASES: usize = 4;
 
 #[derive(Debug)]
 pub struct Compiler {
     parser: Parser,
     scanner: Scanner,
-    chunk: Chunk,
-    locals: Vec<Local>, // locals[UINT8_COUNT];
+    /// the function being compiled
+    current: FunctionState,
+    /// the functions `current` is nested in, innermost last
+    enclosing: Vec<FunctionState>,
+    /// globals declared so far, carried across `interpret` calls by the VM
+    pub(crate) globals: HashMap<String, Global>,
+    /// globals marked `export`, the only ones importers can see
+    pub(crate) exports: HashSet<String>,
+    /// what top-level expression statements do with their value
+    pub(crate) mode: ScriptMode,
+    /// errors and warnings, one line each, for the VM to report
+    pub(crate) diagnostics: Vec<String>,
+    /// where the code of the left operand of the infix rule being compiled starts
+    operand_start: usize,
+    /// 0 leaves the bytecode as emitted, 1 runs the peephole pass over each function and
+    /// 2 adds superinstructions to it
+    pub(crate) opt_level: u8,
+}
+
+/// compilation state of a single function
+#[derive(Debug)]
+struct FunctionState {
+    function: Function,
+    function_type: FunctionType,
+    locals: Vec<Local>,
     local_count: usize,
+    upvalues: Vec<UpvalueRef>,
     scope_depth: usize,
+    /// the pool index of each number and string constant, so repeats share a slot
+    constants: HashMap<ConstantKey, usize>,
+}
+
+/// a constant compared by value, functions are never shared
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    boolean(bool),
+    /// by bits, so `0.0` and `-0.0` stay apart
+    number(u64),
+    int(i64),
+    string(String),
+    nil,
+}
+
+impl ConstantKey {
+    fn of(value: &Value) -> Option<Self> {
+        let key = if value.is_bool() {
+            ConstantKey::boolean(value.as_bool())
+        } else if value.is_int() {
+            ConstantKey::int(value.as_int())
+        } else if value.is_number() {
+            ConstantKey::number(value.as_number().to_bits())
+        } else if value.is_string() {
+            ConstantKey::string(value.as_string().to_string())
+        } else if value.is_nil() {
+            ConstantKey::nil
+        } else {
+            return None;
+        };
+        return Some(key);
+    }
+}
+
+/// how a script treats the value of a top-level expression statement
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum ScriptMode {
+    /// discard it
+    MODE_RUN,
+    /// print it, for REPL input
+    MODE_ECHO,
+    /// keep the last one in slot zero and return it from the script, for `VM::eval`
+    MODE_EVAL,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum FunctionType {
+    TYPE_FUNCTION,
+    TYPE_SCRIPT,
 }
 
 #[derive(Debug, Clone)]
@@ -29,22 +123,30 @@ pub struct Parser {
     pub previous: Token,
     pub had_error: bool,
     pub panic_mode: bool,
+    /// the doc comment lines in front of `current`
+    pub doc: Option<String>,
 }
 
 #[repr(u8)]
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
 enum Precedence {
     PREC_NONE = 0,
-    PREC_ASSIGNMENT = 1, // =
-    PREC_OR = 2,         // or
-    PREC_AND = 3,        // and
-    PREC_EQUALITY = 4,   // == !=
-    PREC_COMPARISON = 5, // < > <= >=
-    PREC_TERM = 6,       // + -
-    PREC_FACTOR = 7,     // * /
-    PREC_UNARY = 8,      // ! -
-    PREC_CALL = 9,       // . ()
-    PREC_PRIMARY = 10,
+    PREC_ASSIGNMENT = 1,  // =
+    PREC_CONDITIONAL = 2, // ?:
+    PREC_COALESCE = 3,    // ??
+    PREC_OR = 4,          // or
+    PREC_AND = 5,         // and
+    PREC_BIT_OR = 6,      // |
+    PREC_BIT_XOR = 7,     // ^
+    PREC_BIT_AND = 8,     // &
+    PREC_EQUALITY = 9,    // == !=
+    PREC_COMPARISON = 10, // < > <= >=
+    PREC_SHIFT = 11,      // << >>
+    PREC_TERM = 12,       // + -
+    PREC_FACTOR = 13,     // * / %
+    PREC_UNARY = 14,      // ! - ~
+    PREC_CALL = 15,       // . ()
+    PREC_PRIMARY = 16,
 }
 
 struct ParseRule {
@@ -57,26 +159,108 @@ struct ParseRule {
 struct Local {
     name: Token,
     depth: isize,
+    is_const: bool,
+    /// captured by a closure, so it is moved off the stack when it goes out of scope
+    is_captured: bool,
+}
+
+/// where a closure finds a captured variable when it is created: a local slot of the
+/// enclosing function, or one of the enclosing function's own upvalues
+#[derive(Debug, Clone)]
+struct UpvalueRef {
+    index: u16,
+    is_local: bool,
+    is_const: bool,
+}
+
+/// compile-time knowledge about a global variable
+#[derive(Debug, Clone)]
+pub struct Global {
+    pub(crate) is_const: bool,
+    /// the literal a constant was initialized with, inlined at every use site
+    value: Option<Value>,
+    /// the `///` comment in front of the declaration
+    pub(crate) doc: Option<String>,
+}
+
+impl FunctionState {
+    fn new(function_type: FunctionType, name: Option<String>) -> Self {
+        // slot zero holds the function being called
+        let callee = Local {
+            name: Token::default(),
+            depth: 0,
+            is_const: false,
+            is_captured: false,
+        };
+        Self {
+            function: Function::new(name),
+            function_type,
+            locals: vec![callee],
+            local_count: 1,
+            upvalues: vec![],
+            scope_depth: 0,
+            constants: HashMap::new(),
+        }
+    }
+}
+
+/// one arm of a `match` statement. Arm bodies are compiled first, the dispatch code that
+/// jumps back into them is emitted once every pattern is known.
+struct MatchArm {
+    /// literal alternatives, empty for a wildcard, binding or list pattern
+    literals: Vec<Value>,
+    /// the element count of a list pattern
+    list: Option<usize>,
+    /// offset of the first instruction of the arm body
+    body: usize,
+}
+
+impl MatchArm {
+    /// wildcard and binding patterns match anything
+    fn is_irrefutable(&self) -> bool {
+        self.literals.is_empty() && self.list.is_none()
+    }
 }
 
 impl Compiler {
     pub fn new(parser: Parser, scanner: Scanner, chunk: Chunk) -> Self {
+        let mut script = FunctionState::new(TYPE_SCRIPT, None);
+        script.function.chunk = chunk;
         Self {
             parser,
             scanner,
-            chunk,
-
-            locals: vec![],
-            local_count: 0,
-            scope_depth: 0,
+            current: script,
+            enclosing: vec![],
+            globals: HashMap::new(),
+            exports: HashSet::new(),
+            mode: MODE_RUN,
+            diagnostics: vec![],
+            operand_start: 0,
+            opt_level: 2,
         }
     }
-    pub fn compile(&mut self) -> bool {
+
+    /// compile the whole source into the top-level script function
+    pub fn compile(&mut self) -> Option<Function> {
         self.advance();
+        if self.mode == MODE_EVAL {
+            // slot zero holds the script closure until an expression statement replaces it
+            self.emit_byte(OP_NIL);
+            self.emit_bytes(OP_SET_LOCAL, 0);
+            self.emit_byte(OP_POP);
+        }
         while !self.match_(TOKEN_EOF) {
             self.declaration();
         }
-        !self.parser.had_error
+        let function = self.end_compiler();
+        if self.parser.had_error {
+            return None;
+        }
+        Some(function)
+    }
+
+    fn current_chunk(&mut self) -> &mut Chunk {
+        &mut self.current.function.chunk
     }
 
     fn error_at_current(&mut self, message: &str) {
@@ -92,31 +276,56 @@ impl Compiler {
             return;
         }
         self.parser.panic_mode = true;
-        eprint!("[line {}] Error", token.line);
+        let mut diagnostic = format!("[line {}] Error", token.line);
         if token.r#type == TOKEN_EOF {
-            eprint!(" at end");
+            diagnostic.push_str(" at end");
         } else if token.r#type == TOKEN_ERROR {
             // Nothing.
         } else {
-            eprint!(" at '{}'", token.lexume);
+            diagnostic.push_str(&format!(" at '{}'", token.lexume));
         }
-        eprint!(": {}\n", message);
+        self.diagnostics
+            .push(format!("{}: {}", diagnostic, message));
         self.parser.had_error = true;
     }
 
+    fn warning_at(&mut self, token: &Token, message: &str) {
+        if self.parser.panic_mode {
+            return;
+        }
+        let mut diagnostic = format!("[line {}] Warning", token.line);
+        if token.r#type == TOKEN_EOF {
+            diagnostic.push_str(" at end");
+        } else {
+            diagnostic.push_str(&format!(" at '{}'", token.lexume));
+        }
+        self.diagnostics
+            .push(format!("{}: {}", diagnostic, message));
+    }
+
     fn advance(&mut self) {
         self.parser.previous = self.parser.current.clone();
+        let mut doc: Vec<String> = vec![];
         loop {
             self.parser.current = self.scanner.scan_token();
+            if self.parser.current.r#type == TOKEN_DOC_COMMENT {
+                doc.push(doc_text(&self.parser.current.lexume));
+                continue;
+            }
             if self.parser.current.r#type != TOKEN_ERROR {
                 break;
             }
             self.error_at_current(&self.parser.current.lexume.clone());
         }
+        self.parser.doc = if doc.is_empty() {
+            None
+        } else {
+            Some(doc.join("\n"))
+        };
     }
 
     fn consume(&mut self, r#type: TokenType, message: &str) {
-        if (self.parser.current.r#type == r#type) {
+        if self.parser.current.r#type == r#type {
             self.advance();
             return;
         }
@@ -136,7 +345,8 @@ impl Compiler {
     }
 
     fn emit_byte<B: Into<u8>>(&mut self, byte: B) {
-        self.chunk.write_chunk(byte, self.parser.previous.line);
+        let line = self.parser.previous.line;
+        self.current_chunk().write_chunk(byte, line);
     }
 
     fn emit_bytes<B1, B2>(&mut self, byte1: B1, byte2: B2)
@@ -148,39 +358,76 @@ impl Compiler {
         self.emit_byte(byte2.into());
     }
 
-    fn emit_jump(&mut self, instruction: u8) -> usize {
+    fn emit_jump(&mut self, instruction: OpCode) -> usize {
         self.emit_byte(instruction);
         self.emit_byte(0xff);
         self.emit_byte(0xff);
-        return self.chunk.count() - 2;
+        return self.current_chunk().count() - 2;
     }
 
-    fn end_compiler(&mut self) {
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_byte(OP_LOOP);
+        // +2 to adjust for the OP_LOOP operands themselves.
+        let offset = self.current_chunk().count() - loop_start + 2;
+        self.emit_short(offset);
+    }
+
+    fn emit_short(&mut self, value: usize) {
+        if value > u16::MAX as usize {
+            self.error("Too much code to jump over.");
+        }
+        self.emit_byte(((value >> 8) & 0xff) as u8);
+        self.emit_byte((value & 0xff) as u8);
+    }
+
+    /// finish the current function and return to the enclosing one
+    fn end_compiler(&mut self) -> Function {
         self.emit_return();
-        if (!self.parser.had_error) {
-            // ifdef DEBUG_PRINT_CODE
-            disassemble_chunk(&mut self.chunk, "code");
+        let enclosing = self
+            .enclosing
+            .pop()
+            .unwrap_or_else(|| FunctionState::new(TYPE_SCRIPT, None));
+        let state = std::mem::replace(&mut self.current, enclosing);
+        let mut function = state.function;
+        function.upvalue_count = state.upvalues.len();
+        if self.opt_level > 0 && !self.parser.had_error {
+            if let Err(message) = optimize(&mut function.chunk, self.opt_level) {
+                self.error(&message);
+            }
         }
+        #[cfg(feature = "debug_print_code")]
+        if !self.parser.had_error {
+            let name = function.name.clone().unwrap_or("<script>".into());
+            disassemble_chunk(&mut function.chunk, &name);
+        }
+        function
     }
 
     fn begin_scope(&mut self) {
-        self.scope_depth += 1;
+        self.current.scope_depth += 1;
     }
 
     fn end_scope(&mut self) {
-        self.scope_depth -= 1;
-        while self.local_count > 0
-            && self.locals[self.local_count - 1].depth > self.scope_depth as isize
+        self.current.scope_depth -= 1;
+        while self.current.local_count > 0
+            && self.current.locals[self.current.local_count - 1].depth
+                > self.current.scope_depth as isize
         {
-            self.emit_byte(OP_POP);
-            self.local_count -= 1;
+            if self.current.locals[self.current.local_count - 1].is_captured {
+                self.emit_byte(OP_CLOSE_UPVALUE);
+            } else {
+                self.emit_byte(OP_POP);
+            }
+            self.current.locals.pop();
+            self.current.local_count -= 1;
         }
     }
 
     fn binary(&mut self, can_assign: bool) {
+        let start = self.operand_start;
         let operator_type = self.parser.previous.r#type;
         let rule = self.get_rule(operator_type, can_assign);
-        self.parse_precedence((rule.unwrap().precedence.add(1)));
+        self.parse_precedence(rule.unwrap().precedence.add(1));
 
         match operator_type {
             TOKEN_BANG_EQUAL => self.emit_bytes(OP_EQUAL, OP_NOT),
@@ -193,11 +440,40 @@ impl Compiler {
             TOKEN_MINUS => self.emit_byte(OP_SUBTRACT),
             TOKEN_STAR => self.emit_byte(OP_MULTIPLY),
             TOKEN_SLASH => self.emit_byte(OP_DIVIDE),
+            TOKEN_PERCENT => self.emit_byte(OP_MODULO),
+            TOKEN_AMPERSAND => self.emit_byte(OP_BIT_AND),
+            TOKEN_PIPE => self.emit_byte(OP_BIT_OR),
+            TOKEN_CARET => self.emit_byte(OP_BIT_XOR),
+            TOKEN_LESS_LESS => self.emit_byte(OP_SHIFT_LEFT),
+            TOKEN_GREATER_GREATER => self.emit_byte(OP_SHIFT_RIGHT),
             _ => return,
         }
+        self.fold_since(start);
     }
 
-    fn literal(&mut self, can_assign: bool) {
+    /// `cond ? then : else`, right-associative
+    fn conditional(&mut self, _can_assign: bool) {
+        let else_jump = self.emit_jump(OP_JUMP_IF_FALSE);
+        self.emit_byte(OP_POP);
+        self.expression();
+        self.consume(TOKEN_COLON, "Expect ':' after then branch of conditional.");
+
+        let end_jump = self.emit_jump(OP_JUMP);
+        self.patch_jump(else_jump);
+        self.emit_byte(OP_POP);
+        self.parse_precedence(PREC_CONDITIONAL);
+        self.patch_jump(end_jump);
+    }
+
+    /// `a ?? b` evaluates `b` only when `a` is nil
+    fn coalesce(&mut self, _can_assign: bool) {
+        let end_jump = self.emit_jump(OP_JUMP_IF_NOT_NIL);
+        self.emit_byte(OP_POP);
+        self.parse_precedence(PREC_COALESCE);
+        self.patch_jump(end_jump);
+    }
+
+    fn literal(&mut self, _can_assign: bool) {
         match self.parser.previous.r#type {
             TOKEN_FALSE => self.emit_byte(OP_FALSE),
             TOKEN_NIL => self.emit_byte(OP_NIL),
@@ -206,24 +482,39 @@ impl Compiler {
         }
     }
 
-    fn get_rule(&self, operator_type: TokenType, can_assign: bool) -> Option<ParseRule> {
+    fn get_rule(&self, operator_type: TokenType, _can_assign: bool) -> Option<ParseRule> {
         match operator_type {
             TOKEN_LEFT_PAREN => Some(ParseRule::new(
                 Some(|c: &mut Compiler, can_assign: bool| c.grouping(can_assign)),
-                None,
-                PREC_NONE,
+                Some(|c: &mut Compiler, can_assign: bool| c.call(can_assign)),
+                PREC_CALL,
             )),
             TOKEN_RIGHT_PAREN => Some(ParseRule::new(None, None, PREC_NONE)),
             TOKEN_LEFT_BRACE => Some(ParseRule::new(None, None, PREC_NONE)),
             TOKEN_RIGHT_BRACE => Some(ParseRule::new(None, None, PREC_NONE)),
+            TOKEN_LEFT_BRACKET => Some(ParseRule::new(
+                None,
+                Some(|c: &mut Compiler, can_assign: bool| c.subscript(can_assign)),
+                PREC_CALL,
+            )),
+            TOKEN_RIGHT_BRACKET => Some(ParseRule::new(None, None, PREC_NONE)),
+            TOKEN_DOT_DOT_DOT => Some(ParseRule::new(None, None, PREC_NONE)),
             TOKEN_COMMA => Some(ParseRule::new(None, None, PREC_NONE)),
 
-            TOKEN_COMMA => Some(ParseRule::new(None, None, PREC_NONE)),
-            TOKEN_DOT => Some(ParseRule::new(None, None, PREC_NONE)),
+            TOKEN_DOT => Some(ParseRule::new(
+                None,
+                Some(|c: &mut Compiler, can_assign: bool| c.dot(can_assign)),
+                PREC_CALL,
+            )),
+            TOKEN_QUESTION_DOT => Some(ParseRule::new(
+                None,
+                Some(|c: &mut Compiler, can_assign: bool| c.optional_dot(can_assign)),
+                PREC_CALL,
+            )),
             TOKEN_MINUS => Some(ParseRule::new(
                 Some(|c: &mut Compiler, can_assign: bool| c.unary(can_assign)),
                 Some(|c: &mut Compiler, can_assign: bool| c.binary(can_assign)),
-                PREC_NONE,
+                PREC_TERM,
             )),
             TOKEN_PLUS => Some(ParseRule::new(
                 None,
@@ -242,6 +533,29 @@ impl Compiler {
                 Some(|c: &mut Compiler, can_assign: bool| c.binary(can_assign)),
                 PREC_FACTOR,
             )),
+            TOKEN_PERCENT => Some(ParseRule::new(
+                None,
+                Some(|c: &mut Compiler, can_assign: bool| c.binary(can_assign)),
+                PREC_FACTOR,
+            )),
+            TOKEN_QUESTION => Some(ParseRule::new(
+                None,
+                Some(|c: &mut Compiler, can_assign: bool| c.conditional(can_assign)),
+                PREC_CONDITIONAL,
+            )),
+            TOKEN_QUESTION_QUESTION => Some(ParseRule::new(
+                None,
+                Some(|c: &mut Compiler, can_assign: bool| c.coalesce(can_assign)),
+                PREC_COALESCE,
+            )),
+            TOKEN_COLON => Some(ParseRule::new(None, None, PREC_NONE)),
+            TOKEN_PLUS_PLUS | TOKEN_MINUS_MINUS => Some(ParseRule::new(
+                Some(|c: &mut Compiler, can_assign: bool| c.prefix_increment(can_assign)),
+                None,
+                PREC_NONE,
+            )),
+            TOKEN_PLUS_EQUAL | TOKEN_MINUS_EQUAL | TOKEN_STAR_EQUAL | TOKEN_SLASH_EQUAL
+            | TOKEN_PERCENT_EQUAL => Some(ParseRule::new(None, None, PREC_NONE)),
             TOKEN_BANG => Some(ParseRule::new(
                 Some(|c: &mut Compiler, can_assign: bool| c.unary(can_assign)),
                 None,
@@ -285,24 +599,69 @@ impl Compiler {
                 None,
                 PREC_NONE,
             )),
-            TOKEN_STRING => Some(ParseRule::new(None, None, PREC_NONE)),
-            TOKEN_NUMBER => Some(ParseRule::new(
+            TOKEN_STRING => Some(ParseRule::new(
+                Some(|c: &mut Compiler, can_assign: bool| c.string(can_assign)),
+                None,
+                PREC_NONE,
+            )),
+            TOKEN_NUMBER | TOKEN_INTEGER => Some(ParseRule::new(
                 Some(|c: &mut Compiler, can_assign: bool| c.number(can_assign)),
                 None,
                 PREC_NONE,
             )),
             TOKEN_AND => Some(ParseRule::new(None, None, PREC_NONE)),
+            TOKEN_AS => Some(ParseRule::new(None, None, PREC_NONE)),
             TOKEN_CLASS => Some(ParseRule::new(None, None, PREC_NONE)),
+            TOKEN_CONST => Some(ParseRule::new(None, None, PREC_NONE)),
 
             TOKEN_ELSE => Some(ParseRule::new(None, None, PREC_NONE)),
+            TOKEN_EXPORT => Some(ParseRule::new(None, None, PREC_NONE)),
             TOKEN_FALSE => Some(ParseRule::new(
                 Some(|c: &mut Compiler, can_assign: bool| c.literal(can_assign)),
                 None,
                 PREC_NONE,
             )),
             TOKEN_FOR => Some(ParseRule::new(None, None, PREC_NONE)),
-            TOKEN_FUN => Some(ParseRule::new(None, None, PREC_NONE)),
+            TOKEN_FROM => Some(ParseRule::new(None, None, PREC_NONE)),
+            TOKEN_FUN => Some(ParseRule::new(
+                Some(|c: &mut Compiler, can_assign: bool| c.lambda(can_assign)),
+                None,
+                PREC_NONE,
+            )),
             TOKEN_IF => Some(ParseRule::new(None, None, PREC_NONE)),
+            TOKEN_IMPORT => Some(ParseRule::new(None, None, PREC_NONE)),
+            TOKEN_MATCH => Some(ParseRule::new(None, None, PREC_NONE)),
+            TOKEN_PIPE => Some(ParseRule::new(
+                None,
+                Some(|c: &mut Compiler, can_assign: bool| c.binary(can_assign)),
+                PREC_BIT_OR,
+            )),
+            TOKEN_AMPERSAND => Some(ParseRule::new(
+                None,
+                Some(|c: &mut Compiler, can_assign: bool| c.binary(can_assign)),
+                PREC_BIT_AND,
+            )),
+            TOKEN_CARET => Some(ParseRule::new(
+                None,
+                Some(|c: &mut Compiler, can_assign: bool| c.binary(can_assign)),
+                PREC_BIT_XOR,
+            )),
+            TOKEN_TILDE => Some(ParseRule::new(
+                Some(|c: &mut Compiler, can_assign: bool| c.unary(can_assign)),
+                None,
+                PREC_NONE,
+            )),
+            TOKEN_LESS_LESS => Some(ParseRule::new(
+                None,
+                Some(|c: &mut Compiler, can_assign: bool| c.binary(can_assign)),
+                PREC_SHIFT,
+            )),
+            TOKEN_GREATER_GREATER => Some(ParseRule::new(
+                None,
+                Some(|c: &mut Compiler, can_assign: bool| c.binary(can_assign)),
+                PREC_SHIFT,
+            )),
+            TOKEN_ARROW => Some(ParseRule::new(None, None, PREC_NONE)),
 
             TOKEN_NIL => Some(ParseRule::new(
                 Some(|c: &mut Compiler, can_assign: bool| c.literal(can_assign)),
@@ -323,17 +682,191 @@ impl Compiler {
             TOKEN_VAR => Some(ParseRule::new(None, None, PREC_NONE)),
             TOKEN_WHILE => Some(ParseRule::new(None, None, PREC_NONE)),
             TOKEN_ERROR => Some(ParseRule::new(None, None, PREC_NONE)),
+            TOKEN_DOC_COMMENT => Some(ParseRule::new(None, None, PREC_NONE)),
 
             TOKEN_EOF => Some(ParseRule::new(None, None, PREC_NONE)),
         }
     }
 
-    fn grouping(&mut self, can_assign: bool) {
+    fn grouping(&mut self, _can_assign: bool) {
+        if self.is_arrow_function() {
+            self.arrow_function();
+            return;
+        }
         self.expression();
         self.consume(TOKEN_RIGHT_PAREN, "Expect ')' after expression.");
     }
 
+    /// after a '(', look ahead for `ident, ...) =>` without consuming anything
+    fn is_arrow_function(&self) -> bool {
+        let mut scanner = self.scanner.clone();
+        let mut token = self.parser.current.clone();
+        if token.r#type != TOKEN_RIGHT_PAREN {
+            loop {
+                if token.r#type == TOKEN_DOT_DOT_DOT {
+                    token = scanner.scan_code_token();
+                }
+                if token.r#type != TOKEN_IDENTIFIER {
+                    return false;
+                }
+                token = scanner.scan_code_token();
+                if token.r#type == TOKEN_EQUAL {
+                    token = Self::skip_default_value(&mut scanner);
+                }
+                if token.r#type != TOKEN_COMMA {
+                    break;
+                }
+                token = scanner.scan_code_token();
+            }
+            if token.r#type != TOKEN_RIGHT_PAREN {
+                return false;
+            }
+        }
+        scanner.scan_code_token().r#type == TOKEN_ARROW
+    }
+
+    /// skip a parameter default, returning the ',' or ')' that ends it
+    fn skip_default_value(scanner: &mut Scanner) -> Token {
+        let mut depth = 0;
+        loop {
+            let token = scanner.scan_code_token();
+            match token.r#type {
+                TOKEN_LEFT_PAREN => depth += 1,
+                TOKEN_RIGHT_PAREN if depth > 0 => depth -= 1,
+                TOKEN_COMMA | TOKEN_RIGHT_PAREN if depth == 0 => return token,
+                TOKEN_EOF => return token,
+                _ => {}
+            }
+        }
+    }
+
+    /// `(a, b) => expression` or `(a, b) => { statements }`, the '(' already consumed
+    fn arrow_function(&mut self) {
+        let name = format!("anonymous@{}", self.parser.previous.line);
+        self.begin_function(name);
+        self.parameters();
+        self.consume(TOKEN_ARROW, "Expect '=>' after parameters.");
+        if self.match_(TOKEN_LEFT_BRACE) {
+            self.block();
+        } else {
+            self.expression();
+            self.emit_byte(OP_RETURN);
+        }
+        self.end_function();
+    }
+
+    /// `fun (a, b) { ... }` used as an expression
+    fn lambda(&mut self, _can_assign: bool) {
+        let name = format!("anonymous@{}", self.parser.previous.line);
+        self.function(name, None);
+    }
+
+    fn call(&mut self, _can_assign: bool) {
+        let (arg_count, keywords) = self.argument_list();
+        if keywords.is_empty() {
+            self.emit_bytes(OP_CALL, arg_count);
+            return;
+        }
+        let long = keywords.iter().any(|name| *name > u8::MAX as usize);
+        self.emit_byte(if long { OP_CALL_KW_LONG } else { OP_CALL_KW });
+        self.emit_bytes(arg_count, keywords.len() as u8);
+        for name in keywords {
+            self.emit_index(name, long);
+        }
+    }
+
+    /// returns the number of arguments and the name constants of the keyword arguments,
+    /// which always come after the positional ones
+    fn argument_list(&mut self) -> (u8, Vec<usize>) {
+        let mut arg_count = 0;
+        let mut keywords = vec![];
+        let mut names: Vec<String> = vec![];
+        if !self.check(TOKEN_RIGHT_PAREN) {
+            loop {
+                if self.check(TOKEN_IDENTIFIER)
+                    && self.scanner.clone().scan_code_token().r#type == TOKEN_COLON
+                {
+                    self.advance();
+                    let name = self.parser.previous.clone();
+                    if names.contains(&name.lexume) {
+                        self.error(&format!("Duplicate keyword argument '{}'.", name.lexume));
+                    }
+                    names.push(name.lexume.clone());
+                    keywords.push(self.identifier_constant(&name));
+                    self.advance();
+                } else if !keywords.is_empty() {
+                    self.error_at_current("Positional argument can't follow a keyword argument.");
+                }
+                self.expression();
+                if arg_count == u8::MAX as usize {
+                    self.error("Can't have more than 255 arguments.");
+                }
+                arg_count += 1;
+                if !self.match_(TOKEN_COMMA) {
+                    break;
+                }
+            }
+        }
+        self.consume(TOKEN_RIGHT_PAREN, "Expect ')' after arguments.");
+        (arg_count.min(u8::MAX as usize) as u8, keywords)
+    }
+
+    fn dot(&mut self, _can_assign: bool) {
+        let message = format!(
+            "Expect property name after '{}'.",
+            self.parser.previous.lexume
+        );
+        self.consume(TOKEN_IDENTIFIER, &message);
+        let name = self.identifier_constant(&self.parser.previous.clone());
+        if !self.match_(TOKEN_LEFT_PAREN) {
+            self.emit_indexed(OP_GET_PROPERTY, name);
+            return;
+        }
+        let (arg_count, keywords) = self.argument_list();
+        if keywords.is_empty() {
+            self.emit_indexed(OP_INVOKE, name);
+            self.emit_byte(arg_count);
+            return;
+        }
+        let long = keywords
+            .iter()
+            .chain([&name])
+            .any(|name| *name > u8::MAX as usize);
+        self.emit_byte(if long {
+            OP_INVOKE_KW_LONG
+        } else {
+            OP_INVOKE_KW
+        });
+        self.emit_index(name, long);
+        self.emit_bytes(arg_count, keywords.len() as u8);
+        for keyword in keywords {
+            self.emit_index(keyword, long);
+        }
+    }
+
+    /// `a?.b` is nil when `a` is, skipping the access and the rest of the call chain after it
+    fn optional_dot(&mut self, can_assign: bool) {
+        let start = self.operand_start;
+        let present_jump = self.emit_jump(OP_JUMP_IF_NOT_NIL);
+        let end_jump = self.emit_jump(OP_JUMP);
+        self.patch_jump(present_jump);
+        self.dot(can_assign);
+        self.parse_infix(PREC_CALL, can_assign, start);
+        self.patch_jump(end_jump);
+    }
+
+    fn subscript(&mut self, _can_assign: bool) {
+        self.expression();
+        self.consume(TOKEN_RIGHT_BRACKET, "Expect ']' after index.");
+        self.emit_byte(OP_GET_INDEX);
+    }
+
     fn emit_return(&mut self) {
+        if self.mode == MODE_EVAL && self.enclosing.is_empty() {
+            self.emit_bytes(OP_GET_LOCAL, 0);
+        } else {
+            self.emit_byte(OP_NIL);
+        }
         self.emit_byte(OP_RETURN);
     }
 
@@ -347,10 +880,11 @@ impl Compiler {
         self.consume(TOKEN_RIGHT_BRACE, "Expect '}' after block.");
     }
 
-    fn var_declaration(&mut self) {
+    fn var_declaration(&mut self, doc: Option<String>) {
         // parse var name, store its name to constant pool and return constant pool index
         let global = self.parse_variable("Expect variable name.");
-        if (self.match_(TOKEN_EQUAL)) {
+        self.declare_global(&self.parser.previous.clone(), false, None, doc);
+        if self.match_(TOKEN_EQUAL) {
             self.expression();
         } else {
             self.emit_byte(OP_NIL);
@@ -359,10 +893,240 @@ impl Compiler {
         self.define_variable(global);
     }
 
+    /// `import "path" as name;`
+    fn import_declaration(&mut self) {
+        let path = self.module_path("Expect module path after 'import'.");
+        self.consume(TOKEN_AS, "Expect 'as' after module path.");
+        let global = self.parse_variable("Expect module name.");
+        self.declare_global(&self.parser.previous.clone(), false, None, None);
+        self.emit_indexed(OP_IMPORT, path);
+        self.consume(TOKEN_SEMICOLON, "Expect ';' after import.");
+        self.define_variable(global);
+    }
+
+    /// `from "path" import a, b;`
+    fn import_names_declaration(&mut self) {
+        let path = self.module_path("Expect module path after 'from'.");
+        self.consume(TOKEN_IMPORT, "Expect 'import' after module path.");
+        loop {
+            let global = self.parse_variable("Expect name to import.");
+            let name = self.parser.previous.clone();
+            self.declare_global(&name, false, None, None);
+            let constant = self.identifier_constant(&name);
+            self.emit_indexed(OP_IMPORT, path);
+            self.emit_indexed(OP_GET_PROPERTY, constant);
+            self.define_variable(global);
+            if !self.match_(TOKEN_COMMA) {
+                break;
+            }
+        }
+        self.consume(TOKEN_SEMICOLON, "Expect ';' after import.");
+    }
+
+    fn module_path(&mut self, error_message: &str) -> usize {
+        self.consume(TOKEN_STRING, error_message);
+        let path = self.string_literal();
+        self.make_constant(Value::string_val(path))
+    }
+
+    /// `export` in front of a top-level `var`, `const` or `fun` declaration
+    fn export_declaration(&mut self, doc: Option<String>) {
+        if self.current.function_type != TYPE_SCRIPT || self.current.scope_depth > 0 {
+            self.error("Can only export top-level declarations.");
+        }
+        let name = self.scanner.clone().scan_code_token().lexume;
+        if self.match_(TOKEN_VAR) {
+            self.var_declaration(doc);
+        } else if self.match_(TOKEN_CONST) {
+            self.const_declaration(doc);
+        } else if self.match_(TOKEN_FUN) && self.check(TOKEN_IDENTIFIER) {
+            self.fun_declaration(doc);
+        } else {
+            self.error_at_current("Expect declaration after 'export'.");
+            return;
+        }
+        self.exports.insert(name);
+    }
+
+    fn fun_declaration(&mut self, doc: Option<String>) {
+        let global = self.parse_variable("Expect function name.");
+        let name = self.parser.previous.clone();
+        self.declare_global(&name, false, None, doc.clone());
+        // a function may refer to itself, so it is usable before its body is compiled
+        self.mark_initialized();
+        self.function(name.lexume, doc);
+        self.define_variable(global);
+    }
+
+    /// an expression statement starting with an anonymous function, its `fun` already consumed
+    fn lambda_statement(&mut self) {
+        let start = self.current_chunk().count();
+        self.lambda(false);
+        self.parse_infix(PREC_ASSIGNMENT, false, start);
+        self.consume(TOKEN_SEMICOLON, "Expect ';' after expression.");
+        self.emit_byte(OP_POP);
+    }
+
+    /// compile a parameter list and block body into a function object
+    fn function(&mut self, name: String, doc: Option<String>) {
+        self.begin_function(name);
+        self.current.function.doc = doc;
+        self.consume(TOKEN_LEFT_PAREN, "Expect '(' after function name.");
+        self.parameters();
+        self.consume(TOKEN_LEFT_BRACE, "Expect '{' before function body.");
+        self.block();
+        self.end_function();
+    }
+
+    fn begin_function(&mut self, name: String) {
+        let state = FunctionState::new(TYPE_FUNCTION, Some(name));
+        self.enclosing
+            .push(std::mem::replace(&mut self.current, state));
+        self.begin_scope();
+    }
+
+    /// finish the current function and emit the closure that creates it at runtime
+    fn end_function(&mut self) {
+        let upvalues = self.current.upvalues.clone();
+        let function = self.end_compiler();
+        let constant = self.make_constant(Value::obj(Obj::function(Rc::new(function))));
+        self.emit_indexed(OP_CLOSURE, constant);
+        for upvalue in upvalues {
+            self.emit_byte(upvalue.is_local as u8);
+            self.emit_short(upvalue.index as usize);
+        }
+    }
+
+    /// parameters up to and including the closing ')': `a, b = 2, ...rest`
+    fn parameters(&mut self) {
+        if !self.check(TOKEN_RIGHT_PAREN) {
+            loop {
+                if self.match_(TOKEN_DOT_DOT_DOT) {
+                    self.parameter("Expect rest parameter name.");
+                    self.current.function.is_variadic = true;
+                    if !self.check(TOKEN_RIGHT_PAREN) {
+                        self.error_at_current("Rest parameter must be the last parameter.");
+                    }
+                    break;
+                }
+                self.current.function.arity += 1;
+                if self.current.function.arity > u8::MAX as usize {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+                self.parameter("Expect parameter name.");
+                let name = self.parser.previous.lexume.clone();
+                self.current.function.params.push(name);
+                if self.match_(TOKEN_EQUAL) {
+                    self.default_value();
+                } else if !self.current.function.defaults.is_empty() {
+                    self.error("Parameter without a default can't follow one with a default.");
+                }
+                if !self.match_(TOKEN_COMMA) {
+                    break;
+                }
+            }
+        }
+        self.consume(TOKEN_RIGHT_PAREN, "Expect ')' after parameters.");
+    }
+
+    fn parameter(&mut self, error_message: &str) {
+        let constant = self.parse_variable(error_message);
+        self.define_variable(constant);
+    }
+
+    /// a parameter default has to be a literal so the VM can bind it without running code
+    fn default_value(&mut self) {
+        let start = self.current_chunk().count();
+        self.expression();
+        let value = self.literal_since(start);
+        self.current_chunk().truncate(start);
+        if value.is_none() {
+            self.error("Default value must be a constant.");
+        }
+        self.current
+            .function
+            .defaults
+            .push(value.unwrap_or_default());
+    }
+
+    fn const_declaration(&mut self, doc: Option<String>) {
+        let global = self.parse_variable("Expect constant name.");
+        let name = self.parser.previous.clone();
+        self.consume(TOKEN_EQUAL, "Expect '=' after constant name.");
+        let start = self.current_chunk().count();
+        self.expression();
+        self.consume(TOKEN_SEMICOLON, "Expect ';' after constant declaration.");
+
+        if self.current.scope_depth > 0 {
+            self.current.locals[self.current.local_count - 1].is_const = true;
+        } else {
+            let value = self.literal_since(start);
+            self.declare_global(&name, true, value, doc);
+        }
+        self.define_variable(global);
+    }
+
+    /// record a global declaration in the symbol table
+    fn declare_global(
+        &mut self,
+        name: &Token,
+        is_const: bool,
+        value: Option<Value>,
+        doc: Option<String>,
+    ) {
+        if self.current.scope_depth > 0 {
+            return;
+        }
+        if self.globals.get(&name.lexume).is_some_and(|g| g.is_const) {
+            self.error_at(name, "Already a constant with this name.");
+            return;
+        }
+        self.globals.insert(
+            name.lexume.clone(),
+            Global {
+                is_const,
+                value,
+                doc,
+            },
+        );
+    }
+
+    /// the value of the code emitted since `start` when it is a single literal
+    fn literal_since(&self, start: usize) -> Option<Value> {
+        let chunk = &self.current.function.chunk;
+        let codes = &chunk.codes[start..];
+        // the constant index and the code after a leading OP_CONSTANT
+        let (index, rest) = match OpCode::try_from(*codes.first()?) {
+            Ok(OP_CONSTANT) if codes.len() >= 2 => (codes[1] as usize, &codes[2..]),
+            Ok(OP_CONSTANT_LONG) if codes.len() >= 4 => (read_long(codes, 1), &codes[4..]),
+            _ => (0, codes),
+        };
+        match OpCode::try_from(*codes.first()?) {
+            Ok(OP_CONSTANT | OP_CONSTANT_LONG) if rest.is_empty() => {
+                Some(chunk.constants.values[index].clone())
+            }
+            Ok(OP_CONSTANT | OP_CONSTANT_LONG) if rest == [u8::from(OP_NEGATE)] => {
+                negate_literal(&chunk.constants.values[index])
+            }
+            Ok(OP_NIL) if codes.len() == 1 => Some(Value::nil_val()),
+            Ok(OP_TRUE) if codes.len() == 1 => Some(Value::bool_val(true)),
+            Ok(OP_FALSE) if codes.len() == 1 => Some(Value::bool_val(false)),
+            _ => None,
+        }
+    }
+
     fn expression_statement(&mut self) {
         self.expression();
         self.consume(TOKEN_SEMICOLON, "Expect ';' after expression.");
-        self.emit_byte(OP_POP);
+        let top_level = self.enclosing.is_empty() && self.current.scope_depth == 0;
+        match self.mode {
+            MODE_ECHO if top_level => self.emit_byte(OP_ECHO),
+            MODE_EVAL if top_level => {
+                self.emit_bytes(OP_SET_LOCAL, 0);
+                self.emit_byte(OP_POP);
+            }
+            _ => self.emit_byte(OP_POP),
+        }
     }
 
     fn if_statement(&mut self) {
@@ -371,9 +1135,282 @@ impl Compiler {
         self.consume(TOKEN_RIGHT_PAREN, "Expect ')' after condition.");
 
         let then_jump = self.emit_jump(OP_JUMP_IF_FALSE);
+        self.emit_byte(OP_POP);
         self.statement();
 
+        let else_jump = self.emit_jump(OP_JUMP);
         self.patch_jump(then_jump);
+        self.emit_byte(OP_POP);
+
+        if self.match_(TOKEN_ELSE) {
+            self.statement();
+        }
+        self.patch_jump(else_jump);
+    }
+
+    fn match_statement(&mut self) {
+        let keyword = self.parser.previous.clone();
+        self.consume(TOKEN_LEFT_PAREN, "Expect '(' after 'match'.");
+        // the subject lives in a hidden local, named by the `match` keyword so no identifier
+        // can resolve to it
+        self.begin_scope();
+        self.expression();
+        self.consume(TOKEN_RIGHT_PAREN, "Expect ')' after match value.");
+        self.add_local(keyword);
+        self.mark_initialized();
+        let subject = self.current.local_count - 1;
+
+        self.consume(TOKEN_LEFT_BRACE, "Expect '{' before match arms.");
+        let dispatch_jump = self.emit_jump(OP_JUMP);
+
+        let mut arms: Vec<MatchArm> = vec![];
+        let mut end_jumps = vec![];
+        let mut seen: Vec<Value> = vec![];
+        let mut seen_lists: Vec<usize> = vec![];
+        while !self.check(TOKEN_RIGHT_BRACE) && !self.check(TOKEN_EOF) {
+            let exhausted = arms.iter().any(MatchArm::is_irrefutable);
+            let arm = self.match_arm(subject, &mut seen, &mut seen_lists, exhausted);
+            arms.push(arm);
+            end_jumps.push(self.emit_jump(OP_JUMP));
+        }
+        self.consume(TOKEN_RIGHT_BRACE, "Expect '}' after match arms.");
+
+        self.patch_jump(dispatch_jump);
+        if !self.emit_match_table(subject, &arms) {
+            self.emit_match_chain(subject, &arms);
+        }
+        for end_jump in end_jumps {
+            self.patch_jump(end_jump);
+        }
+        self.end_scope();
+    }
+
+    /// compile `pattern => statement`, returning the arm's patterns and body offset
+    fn match_arm(
+        &mut self,
+        subject: usize,
+        seen: &mut Vec<Value>,
+        seen_lists: &mut Vec<usize>,
+        exhausted: bool,
+    ) -> MatchArm {
+        if exhausted {
+            self.warning_at(&self.parser.current.clone(), "Unreachable match arm.");
+        }
+        let mut literals = vec![];
+        let mut binding = None;
+        let mut elements = None;
+        if self.match_(TOKEN_LEFT_BRACKET) {
+            let pattern = self.list_pattern();
+            if seen_lists.contains(&pattern.len()) {
+                if !exhausted {
+                    self.warning_at(&self.parser.previous.clone(), "Unreachable pattern.");
+                }
+            } else {
+                seen_lists.push(pattern.len());
+            }
+            elements = Some(pattern);
+        } else if self.match_(TOKEN_IDENTIFIER) {
+            if self.parser.previous.lexume != "_" {
+                binding = Some(self.parser.previous.clone());
+            }
+            if self.check(TOKEN_PIPE) {
+                self.error_at_current("Alternative patterns can only contain literals.");
+            }
+        } else {
+            loop {
+                let literal = self.pattern_literal();
+                if seen.iter().any(|v| values_equal(v, &literal)) {
+                    if !exhausted {
+                        self.warning_at(&self.parser.previous.clone(), "Unreachable pattern.");
+                    }
+                } else {
+                    seen.push(literal.clone());
+                }
+                literals.push(literal);
+                if !self.match_(TOKEN_PIPE) {
+                    break;
+                }
+            }
+        }
+        self.consume(TOKEN_ARROW, "Expect '=>' after match pattern.");
+
+        let body = self.current_chunk().count();
+        let list = elements.as_ref().map(Vec::len);
+        match (binding, elements) {
+            (Some(name), _) => {
+                self.begin_scope();
+                self.emit_indexed(OP_GET_LOCAL, subject);
+                self.add_local(name);
+                self.mark_initialized();
+                self.statement();
+                self.end_scope();
+            }
+            (None, Some(elements)) => {
+                self.begin_scope();
+                for (index, element) in elements.into_iter().enumerate() {
+                    let Some(name) = element else {
+                        continue;
+                    };
+                    self.emit_indexed(OP_GET_LOCAL, subject);
+                    self.emit_constant(Value::int_val(index as i64));
+                    self.emit_byte(OP_GET_INDEX);
+                    self.add_local(name);
+                    self.mark_initialized();
+                }
+                self.statement();
+                self.end_scope();
+            }
+            (None, None) => self.statement(),
+        }
+        self.match_(TOKEN_COMMA);
+        MatchArm {
+            literals,
+            list,
+            body,
+        }
+    }
+
+    /// the elements of `[a, _, b]` after the '[', `None` for the ones that bind nothing
+    fn list_pattern(&mut self) -> Vec<Option<Token>> {
+        let mut elements: Vec<Option<Token>> = vec![];
+        if !self.check(TOKEN_RIGHT_BRACKET) {
+            loop {
+                self.consume(TOKEN_IDENTIFIER, "Expect name in list pattern.");
+                let name = self.parser.previous.clone();
+                if name.lexume == "_" {
+                    elements.push(None);
+                } else {
+                    if elements
+                        .iter()
+                        .flatten()
+                        .any(|other| other.lexume == name.lexume)
+                    {
+                        self.error("Already a variable with this name in this scope.");
+                    }
+                    elements.push(Some(name));
+                }
+                if !self.match_(TOKEN_COMMA) {
+                    break;
+                }
+            }
+        }
+        self.consume(TOKEN_RIGHT_BRACKET, "Expect ']' after list pattern.");
+        if elements.len() > u8::MAX as usize {
+            self.error("Can't have more than 255 elements in a list pattern.");
+        }
+        elements
+    }
+
+    fn pattern_literal(&mut self) -> Value {
+        let negate = self.match_(TOKEN_MINUS);
+        self.advance();
+        match self.parser.previous.r#type {
+            TOKEN_NUMBER | TOKEN_INTEGER if negate => {
+                let value = self.number_literal();
+                negate_literal(&value).unwrap_or(value)
+            }
+            TOKEN_NUMBER | TOKEN_INTEGER => self.number_literal(),
+            TOKEN_STRING if !negate => Value::string_val(self.string_literal()),
+            TOKEN_TRUE if !negate => Value::bool_val(true),
+            TOKEN_FALSE if !negate => Value::bool_val(false),
+            TOKEN_NIL if !negate => Value::nil_val(),
+            _ => {
+                self.error("Expect pattern.");
+                Value::nil_val()
+            }
+        }
+    }
+
+    /// test each literal in turn, looping back to the body of the first arm that matches
+    fn emit_match_chain(&mut self, subject: usize, arms: &[MatchArm]) {
+        for arm in arms {
+            if arm.is_irrefutable() {
+                self.emit_loop(arm.body);
+                return;
+            }
+            if let Some(length) = arm.list {
+                self.emit_indexed(OP_GET_LOCAL, subject);
+                self.emit_bytes(OP_MATCH_LIST, length.min(u8::MAX as usize) as u8);
+                let next = self.emit_jump(OP_JUMP_IF_FALSE);
+                self.emit_byte(OP_POP);
+                self.emit_loop(arm.body);
+                self.patch_jump(next);
+                self.emit_byte(OP_POP);
+            }
+            for literal in &arm.literals {
+                self.emit_indexed(OP_GET_LOCAL, subject);
+                self.emit_constant(literal.clone());
+                self.emit_byte(OP_EQUAL);
+                let next = self.emit_jump(OP_JUMP_IF_FALSE);
+                self.emit_byte(OP_POP);
+                self.emit_loop(arm.body);
+                self.patch_jump(next);
+                self.emit_byte(OP_POP);
+            }
+        }
+    }
+
+    /// dispatch through OP_JUMP_TABLE when every pattern is a small integer and the cases are
+    /// dense enough. Returns false, emitting nothing, when the arms don't qualify.
+    fn emit_match_table(&mut self, subject: usize, arms: &[MatchArm]) -> bool {
+        let mut cases: HashMap<i64, usize> = HashMap::new();
+        let mut default = None;
+        for arm in arms {
+            if arm.list.is_some() {
+                return false;
+            }
+            if arm.literals.is_empty() {
+                default = Some(arm.body);
+                break;
+            }
+            for literal in &arm.literals {
+                if !literal.is_number() {
+                    return false;
+                }
+                let n = literal.as_number();
+                if n.fract() != 0.0 || n < i16::MIN as f64 || n > i16::MAX as f64 {
+                    return false;
+                }
+                cases.entry(n as i64).or_insert(arm.body);
+            }
+        }
+        if cases.len() < JUMP_TABLE_MIN_CASES {
+            return false;
+        }
+        let min = *cases.keys().min().unwrap();
+        let max = *cases.keys().max().unwrap();
+        let count = (max - min + 1) as usize;
+        if count > u8::MAX as usize || count > 2 * cases.len() {
+            return false;
+        }
+
+        self.emit_indexed(OP_GET_LOCAL, subject);
+        self.emit_byte(OP_JUMP_TABLE);
+        self.emit_short(min as i16 as u16 as usize);
+        self.emit_byte(count as u8);
+        // every entry is a backward offset from the end of the table, which is also where
+        // execution continues when nothing matches
+        let table_end = self.current_chunk().count() + 2 * (count + 1);
+        let default = default.unwrap_or(table_end);
+        for value in min..=max {
+            let target = cases.get(&value).copied().unwrap_or(default);
+            self.emit_short(table_end - target);
+        }
+        self.emit_short(table_end - default);
+        true
+    }
+
+    fn return_statement(&mut self) {
+        if self.current.function_type == TYPE_SCRIPT {
+            self.error("Can't return from top-level code.");
+        }
+        if self.match_(TOKEN_SEMICOLON) {
+            self.emit_return();
+        } else {
+            self.expression();
+            self.consume(TOKEN_SEMICOLON, "Expect ';' after return value.");
+            self.emit_byte(OP_RETURN);
+        }
     }
 
     fn print_statement(&mut self) {
@@ -385,12 +1422,13 @@ impl Compiler {
     fn synchronize(&mut self) {
         self.parser.panic_mode = false;
         while self.parser.current.r#type != TOKEN_EOF {
-            if (self.parser.previous.r#type == TOKEN_SEMICOLON) {
+            if self.parser.previous.r#type == TOKEN_SEMICOLON {
                 return;
             }
             match self.parser.current.r#type {
-                TOKEN_CLASS | TOKEN_FUN | TOKEN_VAR | TOKEN_FOR | TOKEN_IF | TOKEN_WHILE
-                | TOKEN_PRINT | TOKEN_RETURN => return,
+                TOKEN_CLASS | TOKEN_CONST | TOKEN_FUN | TOKEN_VAR | TOKEN_FOR | TOKEN_IF
+                | TOKEN_WHILE | TOKEN_MATCH | TOKEN_PRINT | TOKEN_RETURN | TOKEN_IMPORT
+                | TOKEN_FROM | TOKEN_EXPORT => return,
                 _ => {}
             }
             self.advance();
@@ -398,8 +1436,23 @@ impl Compiler {
     }
 
     fn declaration(&mut self) {
-        if self.match_(TOKEN_VAR) {
-            self.var_declaration();
+        let doc = self.parser.doc.take();
+        if self.match_(TOKEN_FUN) {
+            if self.check(TOKEN_IDENTIFIER) {
+                self.fun_declaration(doc);
+            } else {
+                self.lambda_statement();
+            }
+        } else if self.match_(TOKEN_VAR) {
+            self.var_declaration(doc);
+        } else if self.match_(TOKEN_CONST) {
+            self.const_declaration(doc);
+        } else if self.match_(TOKEN_IMPORT) {
+            self.import_declaration();
+        } else if self.match_(TOKEN_FROM) {
+            self.import_names_declaration();
+        } else if self.match_(TOKEN_EXPORT) {
+            self.export_declaration(doc);
         } else {
             self.statement();
         }
@@ -413,6 +1466,10 @@ impl Compiler {
             self.print_statement();
         } else if self.match_(TOKEN_IF) {
             self.if_statement();
+        } else if self.match_(TOKEN_MATCH) {
+            self.match_statement();
+        } else if self.match_(TOKEN_RETURN) {
+            self.return_statement();
         } else if self.match_(TOKEN_LEFT_BRACE) {
             self.begin_scope();
             self.block();
@@ -422,66 +1479,218 @@ impl Compiler {
         }
     }
 
-    fn number(&mut self, can_assign: bool) {
-        let value = self
-            .parser
-            .previous
-            .lexume
-            .parse::<f64>()
-            .expect(&format!("{} not a number", self.parser.previous.lexume));
-        self.emit_constant(Value::number_val(value));
+    fn number(&mut self, _can_assign: bool) {
+        let value = self.number_literal();
+        self.emit_constant(value);
     }
 
-    fn string(&mut self, can_assign: bool) {
-        self.emit_constant(Value::string_val(self.parser.previous.lexume.clone()));
-        // TODO trim the leading and trailing quotation marks
+    /// the value of the number token just consumed, which the scanner has already checked
+    /// to be well formed
+    fn number_literal(&mut self) -> Value {
+        let lexume = self.parser.previous.lexume.replace('_', "");
+        if self.parser.previous.r#type == TOKEN_INTEGER {
+            let radix = match lexume.get(..2) {
+                Some("0x" | "0X") => 16,
+                Some("0b" | "0B") => 2,
+                Some("0o" | "0O") => 8,
+                _ => 10,
+            };
+            let digits = if radix == 10 { &lexume } else { &lexume[2..] };
+            let Ok(value) = i64::from_str_radix(digits, radix) else {
+                self.error("Integer literal is too large.");
+                return Value::int_val(0);
+            };
+            return Value::int_val(value);
+        }
+        match lexume.parse::<f64>() {
+            Ok(value) => Value::number_val(value),
+            Err(_) => {
+                self.error("Invalid number literal.");
+                Value::number_val(0.0)
+            }
+        }
+    }
+
+    fn string(&mut self, _can_assign: bool) {
+        let value = self.string_literal();
+        self.emit_constant(Value::string_val(value));
+    }
+
+    /// the previous string token with its leading and trailing quotation marks trimmed
+    fn string_literal(&self) -> String {
+        let lexume = &self.parser.previous.lexume;
+        lexume[1..lexume.len() - 1].to_string()
     }
 
     fn variable(&mut self, can_assign: bool) {
         let previous = &self.parser.previous.clone();
-        self.named_variable(&previous, can_assign);
+        self.named_variable(previous, can_assign);
     }
 
     fn named_variable(&mut self, name: &Token, can_assign: bool) {
-        let (arg, get_op, set_op) = match self.resolve_local(name) {
-            None => (self.identifier_constant(name), OP_GET_LOCAL, OP_SET_LOCAL),
-            Some(arg) => (arg as u8, OP_GET_GLOBAL, OP_SET_GLOBAL),
+        let compound = if can_assign {
+            self.compound_operator()
+        } else {
+            None
         };
+        let increment = self.check(TOKEN_PLUS_PLUS) || self.check(TOKEN_MINUS_MINUS);
+        let assigning = (can_assign && self.check(TOKEN_EQUAL)) || compound.is_some() || increment;
+        if !assigning {
+            if let Some(value) = self.inlined_constant(name) {
+                self.emit_constant(value);
+                return;
+            }
+        }
+        let (arg, get_op, set_op, is_const) = self.resolve_variable(name);
 
-        let (get_op, set_op) = (OP_GET_GLOBAL, OP_SET_GLOBAL);
         if can_assign && self.match_(TOKEN_EQUAL) {
+            if is_const {
+                self.error("Can't assign to a constant.");
+            }
             self.expression();
-            self.emit_bytes(set_op, arg);
+            self.emit_indexed(set_op, arg);
+        } else if let Some(operator) = compound {
+            self.advance();
+            if is_const {
+                self.error("Can't assign to a constant.");
+            }
+            self.emit_indexed(get_op, arg);
+            self.expression();
+            self.emit_byte(operator);
+            self.emit_indexed(set_op, arg);
+        } else if increment {
+            self.advance();
+            if is_const {
+                self.error("Can't assign to a constant.");
+            }
+            let operator = self.increment_operator();
+            // keep the old value underneath the updated one as the result
+            self.emit_indexed(get_op, arg);
+            self.emit_byte(OP_DUP);
+            self.emit_constant(Value::int_val(1));
+            self.emit_byte(operator);
+            self.emit_indexed(set_op, arg);
+            self.emit_byte(OP_POP);
+        } else {
+            self.emit_indexed(get_op, arg);
+        }
+    }
+
+    /// prefix `++name` / `--name`, evaluating to the updated value
+    fn prefix_increment(&mut self, _can_assign: bool) {
+        let operator = self.increment_operator();
+        self.consume(
+            TOKEN_IDENTIFIER,
+            "Expect variable name after increment operator.",
+        );
+        let name = self.parser.previous.clone();
+        let (arg, get_op, set_op, is_const) = self.resolve_variable(&name);
+        if is_const {
+            self.error("Can't assign to a constant.");
+        }
+        self.emit_indexed(get_op, arg);
+        self.emit_constant(Value::int_val(1));
+        self.emit_byte(operator);
+        self.emit_indexed(set_op, arg);
+    }
+
+    /// the arithmetic instruction for the `++` or `--` in the previous token
+    fn increment_operator(&self) -> OpCode {
+        if self.parser.previous.r#type == TOKEN_PLUS_PLUS {
+            OP_ADD
         } else {
-            self.emit_bytes(get_op, arg);
+            OP_SUBTRACT
         }
     }
 
-    fn unary(&mut self, can_assign: bool) {
+    /// the arithmetic instruction for a compound assignment operator in the current token
+    fn compound_operator(&self) -> Option<OpCode> {
+        match self.parser.current.r#type {
+            TOKEN_PLUS_EQUAL => Some(OP_ADD),
+            TOKEN_MINUS_EQUAL => Some(OP_SUBTRACT),
+            TOKEN_STAR_EQUAL => Some(OP_MULTIPLY),
+            TOKEN_SLASH_EQUAL => Some(OP_DIVIDE),
+            TOKEN_PERCENT_EQUAL => Some(OP_MODULO),
+            _ => None,
+        }
+    }
+
+    /// the literal value of a global constant, unless a local shadows it
+    fn inlined_constant(&mut self, name: &Token) -> Option<Value> {
+        if self.resolve_local(name).is_some() || self.resolve_upvalue(0, name).is_some() {
+            return None;
+        }
+        self.globals.get(&name.lexume)?.value.clone()
+    }
+
+    /// returns the operand, get and set instructions of a variable, and whether it is constant
+    fn resolve_variable(&mut self, name: &Token) -> (usize, OpCode, OpCode, bool) {
+        if let Some(arg) = self.resolve_local(name) {
+            let is_const = self.current.locals[arg].is_const;
+            return (arg, OP_GET_LOCAL, OP_SET_LOCAL, is_const);
+        }
+        if let Some(arg) = self.resolve_upvalue(0, name) {
+            let is_const = self.current.upvalues[arg].is_const;
+            return (arg, OP_GET_UPVALUE, OP_SET_UPVALUE, is_const);
+        }
+        let is_const = self
+            .globals
+            .get(&name.lexume)
+            .is_some_and(|global| global.is_const);
+        let arg = self.identifier_constant(name);
+        (arg, OP_GET_GLOBAL, OP_SET_GLOBAL, is_const)
+    }
+
+    fn unary(&mut self, _can_assign: bool) {
+        let start = self.current_chunk().count();
         let operator_type = self.parser.previous.r#type;
         // Compile the operand.
         self.parse_precedence(PREC_UNARY);
-        self.expression();
         // Emit the operator instruction.
         match operator_type {
             TOKEN_BANG => self.emit_byte(OP_NOT),
             TOKEN_MINUS => self.emit_byte(OP_NEGATE),
+            TOKEN_TILDE => self.emit_byte(OP_BIT_NOT),
             _ => return, // Unreachable.
         }
+        self.fold_since(start);
+    }
+
+    /// replace the code emitted since `start` with its value, when it only combines literals
+    /// in ways that can't fail. Code that would fail is kept so the error happens at runtime.
+    fn fold_since(&mut self, start: usize) {
+        let Some(value) = fold(self.current_chunk(), start) else {
+            return;
+        };
+        self.current_chunk().truncate(start);
+        if value.is_nil() {
+            self.emit_byte(OP_NIL);
+        } else if value.is_bool() {
+            self.emit_byte(if value.as_bool() { OP_TRUE } else { OP_FALSE });
+        } else {
+            self.emit_constant(value);
+        }
     }
 
     fn parse_precedence(&mut self, precedence: Precedence) {
+        let start = self.current_chunk().count();
         self.advance();
         let can_assign = precedence <= PREC_ASSIGNMENT;
         let prefix_rule = self
             .get_rule(self.parser.previous.r#type, can_assign)
-            .map(|v| v.prefix);
+            .and_then(|v| v.prefix);
         let Some(prefix_rule) = prefix_rule else {
             self.error("Expect expression.");
             return;
         };
 
-        prefix_rule.map(|f| f(self, can_assign));
+        prefix_rule(self, can_assign);
+        self.parse_infix(precedence, can_assign, start);
+    }
+
+    /// parse infix operators binding at least as tightly as `precedence` after a prefix whose
+    /// code starts at `start`
+    fn parse_infix(&mut self, precedence: Precedence, can_assign: bool, start: usize) {
         while precedence
             <= self
                 .get_rule(self.parser.current.r#type, can_assign)
@@ -499,37 +1708,44 @@ impl Compiler {
                     "rule not found for token type: {:?}",
                     self.parser.previous.r#type
                 ));
-            infix_rule.map(|f| f(self, can_assign));
+            if let Some(infix_rule) = infix_rule {
+                self.operand_start = start;
+                infix_rule(self, can_assign);
+            }
         }
 
-        if can_assign && self.match_(TOKEN_EQUAL) {
+        if can_assign && (self.check(TOKEN_EQUAL) || self.compound_operator().is_some()) {
+            self.advance();
             self.error("Invalid assignment target.");
         }
     }
 
-    fn parse_variable(&mut self, error_message: &str) -> u8 {
+    fn parse_variable(&mut self, error_message: &str) -> usize {
         self.consume(TOKEN_IDENTIFIER, error_message);
         self.declare_variable();
-        if self.scope_depth > 0 {
+        if self.current.scope_depth > 0 {
             return 0;
         }
         return self.identifier_constant(&self.parser.previous.clone());
     }
 
     fn mark_initialized(&mut self) {
-        self.locals[self.local_count - 1].depth = self.scope_depth as isize;
+        if self.current.scope_depth == 0 {
+            return;
+        }
+        self.current.locals[self.current.local_count - 1].depth = self.current.scope_depth as isize;
     }
 
-    fn define_variable(&mut self, global: u8) {
-        if self.scope_depth > 0 {
+    fn define_variable(&mut self, global: usize) {
+        if self.current.scope_depth > 0 {
             self.mark_initialized();
             return;
         }
-        self.emit_bytes(OP_DEFINE_GLOBAL, global);
+        self.emit_indexed(OP_DEFINE_GLOBAL, global);
     }
 
     /// add token to constant pool and return its constant pool index
-    fn identifier_constant(&mut self, name: &Token) -> u8 {
+    fn identifier_constant(&mut self, name: &Token) -> usize {
         return self.make_constant(Value::obj(Obj::string(name.lexume.clone())));
     }
 
@@ -538,38 +1754,92 @@ impl Compiler {
     }
 
     fn resolve_local(&mut self, name: &Token) -> Option<usize> {
-        for i in (0..self.local_count - 1).rev() {
-            let local = &mut self.locals[i];
-            let local_name = local.name.clone();
-            if self.identifiers_equal(name, &local_name) {
-                if local.depth == -1 {
-                    self.error("Can't read local variable in its own initializer.");
-                }
-                return Some(i);
-            }
+        self.resolve_local_at(0, name)
+    }
+
+    /// resolve `name` among the locals of the function `level` steps out from the current one
+    fn resolve_local_at(&mut self, level: usize, name: &Token) -> Option<usize> {
+        let state = self.state_at(level);
+        let i = (0..state.local_count)
+            .rev()
+            .find(|&i| state.locals[i].name.lexume == name.lexume)?;
+        if state.locals[i].depth == -1 {
+            self.error("Can't read local variable in its own initializer.");
+        }
+        return Some(i);
+    }
+
+    /// resolve `name` as a variable captured by the function `level` steps out, adding the
+    /// upvalue to every function between it and the declaring one
+    fn resolve_upvalue(&mut self, level: usize, name: &Token) -> Option<usize> {
+        if level >= self.enclosing.len() {
+            return None;
+        }
+        if let Some(local) = self.resolve_local_at(level + 1, name) {
+            let enclosing = self.state_at(level + 1);
+            enclosing.locals[local].is_captured = true;
+            let is_const = enclosing.locals[local].is_const;
+            return Some(self.add_upvalue(level, local as u16, true, is_const));
+        }
+        if let Some(upvalue) = self.resolve_upvalue(level + 1, name) {
+            let is_const = self.state_at(level + 1).upvalues[upvalue].is_const;
+            return Some(self.add_upvalue(level, upvalue as u16, false, is_const));
+        }
+        None
+    }
+
+    fn add_upvalue(&mut self, level: usize, index: u16, is_local: bool, is_const: bool) -> usize {
+        let state = self.state_at(level);
+        if let Some(existing) = state
+            .upvalues
+            .iter()
+            .position(|upvalue| upvalue.index == index && upvalue.is_local == is_local)
+        {
+            return existing;
+        }
+        if state.upvalues.len() > u8::MAX as usize {
+            self.error("Too many closure variables in function.");
+            return 0;
         }
-        return None;
+        state.upvalues.push(UpvalueRef {
+            index,
+            is_local,
+            is_const,
+        });
+        state.upvalues.len() - 1
+    }
+
+    /// the current function at level 0, the functions enclosing it at higher levels
+    fn state_at(&mut self, level: usize) -> &mut FunctionState {
+        if level == 0 {
+            return &mut self.current;
+        }
+        let index = self.enclosing.len() - level;
+        &mut self.enclosing[index]
     }
 
     fn add_local(&mut self, name: Token) {
-        if self.local_count == u8::MAX as usize {
+        if self.current.local_count == LOCALS_MAX {
             self.error("Too many local variables in function.");
             return;
         }
-        let local = &mut self.locals[self.local_count];
-        self.local_count += 1;
-        local.name = name;
-        local.depth = self.scope_depth as isize;
+        self.current.locals.push(Local {
+            name,
+            depth: -1,
+            is_const: false,
+            is_captured: false,
+        });
+        self.current.local_count += 1;
     }
 
     fn declare_variable(&mut self) {
-        if self.scope_depth == 0 {
+        if self.current.scope_depth == 0 {
             return;
         }
         let name = self.parser.previous.clone();
 
-        for local in self.locals.clone().iter().rev() {
-            if local.depth != -1 && local.depth < self.scope_depth as isize {
+        for local in self.current.locals.clone().iter().rev() {
+            if local.depth != -1 && local.depth < self.current.scope_depth as isize {
                 break;
             }
             if self.identifiers_equal(&name, &local.name) {
@@ -581,29 +1851,60 @@ impl Compiler {
 
     fn emit_constant(&mut self, value: Value) {
         let constant = self.make_constant(value);
-        self.emit_bytes(OP_CONSTANT, constant);
+        self.emit_indexed(OP_CONSTANT, constant);
+    }
+
+    /// emit `op` with a constant index or local slot, switching to its `_LONG` form when the
+    /// operand doesn't fit in a byte
+    fn emit_indexed(&mut self, op: OpCode, index: usize) {
+        if index <= u8::MAX as usize {
+            self.emit_bytes(op, index as u8);
+            return;
+        }
+        let Some(long) = op.long_form() else {
+            self.error("Too many constants in one chunk.");
+            return;
+        };
+        self.emit_byte(long);
+        self.emit_index(index, true);
+    }
+
+    /// emit a constant index operand, 24-bit big-endian when `long`
+    fn emit_index(&mut self, index: usize, long: bool) {
+        if long {
+            self.emit_bytes((index >> 16) as u8, (index >> 8) as u8);
+        }
+        self.emit_byte(index as u8);
     }
 
     fn patch_jump(&mut self, offset: usize) {
         // -2 to adjust for the bytecode for the jump offset itself.
-        let jump = self.chunk.count() - offset - 2;
+        let jump = self.current_chunk().count() - offset - 2;
 
         if jump > u16::MAX as usize {
             self.error("Too much code to jump over.");
         }
 
-        self.chunk.codes[offset] = ((jump >> 8) & 0xff) as u8;
-        self.chunk.codes[offset + 1] = (jump & 0xff) as u8;
+        self.current_chunk().codes[offset] = ((jump >> 8) & 0xff) as u8;
+        self.current_chunk().codes[offset + 1] = (jump & 0xff) as u8;
     }
 
-    /// add value to constant pool and return its pool index. ensure pool index < u8::MAX
-    fn make_constant(&mut self, value: Value) -> u8 {
-        let constant = self.chunk.add_constant(value);
-        if (constant > u8::MAX as usize) {
+    /// add value to constant pool and return its pool index, reusing the slot of an equal
+    /// number or string
+    fn make_constant(&mut self, value: Value) -> usize {
+        let key = ConstantKey::of(&value);
+        if let Some(index) = key.as_ref().and_then(|key| self.current.constants.get(key)) {
+            return *index;
+        }
+        let constant = self.current_chunk().add_constant(value);
+        if constant > CONSTANT_LONG_MAX {
             self.error("Too many constants in one chunk.");
             return 0;
         }
-        constant as u8
+        if let Some(key) = key {
+            self.current.constants.insert(key, constant);
+        }
+        constant
     }
 }
 
@@ -614,6 +1915,7 @@ impl Parser {
             previous,
             had_error: false,
             panic_mode: false,
+            doc: None,
         }
     }
 }
@@ -621,13 +1923,31 @@ impl Parser {
 impl Precedence {
     fn add(&self, n: u8) -> Self {
         let v = (*self as u8) + n;
-        Precedence::try_from(v).expect(&format!("{} can't cast to Precedence", v))
+        Precedence::from(v)
     }
 }
 
 impl From<u8> for Precedence {
     fn from(value: u8) -> Self {
-        todo!()
+        match value {
+            0 => PREC_NONE,
+            1 => PREC_ASSIGNMENT,
+            2 => PREC_CONDITIONAL,
+            3 => PREC_COALESCE,
+            4 => PREC_OR,
+            5 => PREC_AND,
+            6 => PREC_BIT_OR,
+            7 => PREC_BIT_XOR,
+            8 => PREC_BIT_AND,
+            9 => PREC_EQUALITY,
+            10 => PREC_COMPARISON,
+            11 => PREC_SHIFT,
+            12 => PREC_TERM,
+            13 => PREC_FACTOR,
+            14 => PREC_UNARY,
+            15 => PREC_CALL,
+            _ => PREC_PRIMARY,
+        }
     }
 }
 
@@ -644,3 +1964,23 @@ impl ParseRule {
         }
     }
 }
+
+/// `-literal` for a number literal, None for anything else or when an int would overflow
+fn negate_literal(value: &Value) -> Option<Value> {
+    if value.is_int() {
+        return value.as_int().checked_neg().map(Value::int_val);
+    }
+    if value.is_number() {
+        return Some(Value::number_val(-value.as_number()));
+    }
+    return None;
+}
+
+/// the text of a `///` comment line
+fn doc_text(comment: &str) -> String {
+    let text = comment.trim_start_matches('/');
+    text.strip_prefix(' ')
+        .unwrap_or(text)
+        .trim_end()
+        .to_string()
+}
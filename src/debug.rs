@@ -1,5 +1,10 @@
-use crate::chunk::{Chunk, OpCode};
-use crate::value::{print_value, Value};
+#![cfg_attr(
+    not(any(feature = "debug_print_code", feature = "debug_trace_execution")),
+    allow(dead_code)
+)]
+
+use crate::chunk::{read_long, Chunk, OpCode};
+use crate::object::Obj;
 
 pub fn disassemble_chunk(chunk: &mut Chunk, name: &str) {
     print!("== {} ==\n", name);
@@ -15,10 +20,10 @@ pub fn disassemble_chunk(chunk: &mut Chunk, name: &str) {
 /// returns a number to tell the caller the offset of the beginning of the next instruction
 pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
     print!("{:04} ", offset);
-    if offset > 0 && chunk.lines[offset] == chunk.lines[offset - 1] {
+    if offset > 0 && chunk.get_line(offset) == chunk.get_line(offset - 1) {
         print!("   | ");
     } else {
-        print!("{:4} ", chunk.lines[offset]);
+        print!("{:4} ", chunk.get_line(offset));
     }
     let instruction = chunk.codes[offset];
     match OpCode::try_from(instruction) {
@@ -28,6 +33,7 @@ pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
             OpCode::OP_TRUE => simple_instruction("OP_TRUE", offset),
             OpCode::OP_FALSE => simple_instruction("OP_FALSE", offset),
             OpCode::OP_POP => simple_instruction("OP_POP", offset),
+            OpCode::OP_DUP => simple_instruction("OP_DUP", offset),
             OpCode::OP_GET_LOCAL => byte_instruction("OP_GET_LOCAL", chunk, offset),
             OpCode::OP_SET_LOCAL => byte_instruction("OP_SET_LOCAL", chunk, offset),
 
@@ -42,9 +48,60 @@ pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
             OpCode::OP_SUBTRACT => simple_instruction("OP_SUBTRACT", offset),
             OpCode::OP_MULTIPLY => simple_instruction("OP_MULTIPLY", offset),
             OpCode::OP_DIVIDE => simple_instruction("OP_DIVIDE", offset),
+            OpCode::OP_MODULO => simple_instruction("OP_MODULO", offset),
+            OpCode::OP_BIT_AND => simple_instruction("OP_BIT_AND", offset),
+            OpCode::OP_BIT_OR => simple_instruction("OP_BIT_OR", offset),
+            OpCode::OP_BIT_XOR => simple_instruction("OP_BIT_XOR", offset),
+            OpCode::OP_BIT_NOT => simple_instruction("OP_BIT_NOT", offset),
+            OpCode::OP_SHIFT_LEFT => simple_instruction("OP_SHIFT_LEFT", offset),
+            OpCode::OP_SHIFT_RIGHT => simple_instruction("OP_SHIFT_RIGHT", offset),
+            OpCode::OP_ECHO => simple_instruction("OP_ECHO", offset),
+            OpCode::OP_INVOKE => invoke_instruction("OP_INVOKE", chunk, offset),
+            OpCode::OP_INVOKE_KW => invoke_instruction("OP_INVOKE_KW", chunk, offset),
             OpCode::OP_NOT => simple_instruction("OP_NOT", offset),
             OpCode::OP_NEGATE => simple_instruction("OP_NEGATE", offset),
             OpCode::OP_PRINT => simple_instruction("OP_PRINT", offset),
+            OpCode::OP_JUMP => jump_instruction("OP_JUMP", 1, chunk, offset),
+            OpCode::OP_JUMP_IF_FALSE => jump_instruction("OP_JUMP_IF_FALSE", 1, chunk, offset),
+            OpCode::OP_JUMP_IF_NOT_NIL => jump_instruction("OP_JUMP_IF_NOT_NIL", 1, chunk, offset),
+            OpCode::OP_LOOP => jump_instruction("OP_LOOP", -1, chunk, offset),
+            OpCode::OP_JUMP_TABLE => jump_table_instruction("OP_JUMP_TABLE", chunk, offset),
+            OpCode::OP_GET_UPVALUE => byte_instruction("OP_GET_UPVALUE", chunk, offset),
+            OpCode::OP_SET_UPVALUE => byte_instruction("OP_SET_UPVALUE", chunk, offset),
+            OpCode::OP_CALL => byte_instruction("OP_CALL", chunk, offset),
+            OpCode::OP_CALL_KW => call_kw_instruction("OP_CALL_KW", chunk, offset),
+            OpCode::OP_GET_INDEX => simple_instruction("OP_GET_INDEX", offset),
+            OpCode::OP_IMPORT => constant_instruction("OP_IMPORT", chunk, offset),
+            OpCode::OP_GET_PROPERTY => constant_instruction("OP_GET_PROPERTY", chunk, offset),
+            OpCode::OP_CLOSURE => closure_instruction("OP_CLOSURE", chunk, offset),
+            OpCode::OP_CLOSE_UPVALUE => simple_instruction("OP_CLOSE_UPVALUE", offset),
+            OpCode::OP_CONSTANT_LONG => constant_instruction("OP_CONSTANT_LONG", chunk, offset),
+            OpCode::OP_GET_GLOBAL_LONG => constant_instruction("OP_GET_GLOBAL_LONG", chunk, offset),
+            OpCode::OP_DEFINE_GLOBAL_LONG => {
+                constant_instruction("OP_DEFINE_GLOBAL_LONG", chunk, offset)
+            }
+            OpCode::OP_SET_GLOBAL_LONG => constant_instruction("OP_SET_GLOBAL_LONG", chunk, offset),
+            OpCode::OP_GET_PROPERTY_LONG => {
+                constant_instruction("OP_GET_PROPERTY_LONG", chunk, offset)
+            }
+            OpCode::OP_CLOSURE_LONG => closure_instruction("OP_CLOSURE_LONG", chunk, offset),
+            OpCode::OP_INVOKE_LONG => invoke_instruction("OP_INVOKE_LONG", chunk, offset),
+            OpCode::OP_CALL_KW_LONG => call_kw_instruction("OP_CALL_KW_LONG", chunk, offset),
+            OpCode::OP_INVOKE_KW_LONG => invoke_instruction("OP_INVOKE_KW_LONG", chunk, offset),
+            OpCode::OP_IMPORT_LONG => constant_instruction("OP_IMPORT_LONG", chunk, offset),
+            OpCode::OP_GET_LOCAL_LONG => long_instruction("OP_GET_LOCAL_LONG", chunk, offset),
+            OpCode::OP_SET_LOCAL_LONG => long_instruction("OP_SET_LOCAL_LONG", chunk, offset),
+            OpCode::OP_NOT_EQUAL => simple_instruction("OP_NOT_EQUAL", offset),
+            OpCode::OP_GREATER_EQUAL => simple_instruction("OP_GREATER_EQUAL", offset),
+            OpCode::OP_LESS_EQUAL => simple_instruction("OP_LESS_EQUAL", offset),
+            OpCode::OP_ADD_LOCAL_CONST => {
+                local_constant_instruction("OP_ADD_LOCAL_CONST", chunk, offset)
+            }
+            OpCode::OP_LESS_LOCALS_JUMP => {
+                locals_jump_instruction("OP_LESS_LOCALS_JUMP", chunk, offset)
+            }
+            OpCode::OP_INC_LOCAL => byte_instruction("OP_INC_LOCAL", chunk, offset),
+            OpCode::OP_MATCH_LIST => byte_instruction("OP_MATCH_LIST", chunk, offset),
             OpCode::OP_RETURN => simple_instruction("OP_RETURN", offset),
         },
         Err(_) => {
@@ -65,10 +122,143 @@ fn byte_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
     return offset + 2;
 }
 
+fn long_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+    let slot = read_long(&chunk.codes, offset + 1);
+    print!("-{:<16} {:4}\n", name, slot);
+    return offset + 4;
+}
+
+fn jump_instruction(name: &str, sign: isize, chunk: &Chunk, offset: usize) -> usize {
+    let jump = ((chunk.codes[offset + 1] as u16) << 8) | chunk.codes[offset + 2] as u16;
+    let target = offset as isize + 3 + sign * jump as isize;
+    print!("{:<16} {:4} -> {}\n", name, offset, target);
+    offset + 3
+}
+
+fn local_constant_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+    let slot = chunk.codes[offset + 1];
+    let constant = chunk.codes[offset + 2] as usize;
+    print!(
+        "-{:<16} {:4} {:4} '{}'\n",
+        name,
+        slot,
+        constant,
+        chunk.constants.values[constant].repr()
+    );
+    offset + 3
+}
+
+fn locals_jump_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+    let (a, b) = (chunk.codes[offset + 1], chunk.codes[offset + 2]);
+    let jump = ((chunk.codes[offset + 3] as usize) << 8) | chunk.codes[offset + 4] as usize;
+    print!("-{:<16} {:4} {:4} -> {}\n", name, a, b, offset + 5 + jump);
+    offset + 5
+}
+
+fn jump_table_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+    let read_short = |at: usize| ((chunk.codes[at] as u16) << 8) | chunk.codes[at + 1] as u16;
+    let min = read_short(offset + 1) as i16;
+    let count = chunk.codes[offset + 3] as usize;
+    let table = offset + 4;
+    let table_end = table + 2 * (count + 1);
+    print!(
+        "{:<16} {:4} [{}..{}]\n",
+        name,
+        offset,
+        min,
+        min as isize + count as isize
+    );
+    for i in 0..count {
+        let target = table_end - read_short(table + 2 * i) as usize;
+        print!(
+            "                      | {:>6} -> {}\n",
+            min as isize + i as isize,
+            target
+        );
+    }
+    let target = table_end - read_short(table + 2 * count) as usize;
+    print!("                      | {:>6} -> {}\n", "_", target);
+    table_end
+}
+
+fn call_kw_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+    let arg_count = chunk.codes[offset + 1];
+    print!("{:<16} {:4}", name, arg_count);
+    let long = chunk.codes[offset] == OpCode::OP_CALL_KW_LONG as u8;
+    keywords(chunk, offset + 2, long)
+}
+
+/// `OP_INVOKE_KW` has the keyword names of `OP_CALL_KW` after the method name and arg count
+fn invoke_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+    let (constant, next) = constant_operand(chunk, offset);
+    let arg_count = chunk.codes[next];
+    let method = chunk.constants.values[constant].as_string();
+    print!(
+        "{:<16} ({} args) {:4} '{}'",
+        name, arg_count, constant, method
+    );
+    let op = chunk.codes[offset];
+    if op == OpCode::OP_INVOKE as u8 || op == OpCode::OP_INVOKE_LONG as u8 {
+        print!("\n");
+        return next + 1;
+    }
+    keywords(chunk, next + 1, op == OpCode::OP_INVOKE_KW_LONG as u8)
+}
+
+/// print the `count:u8 name:u8...` keyword list at `offset`, returns the offset after it.
+/// the names are 24-bit when `long`
+fn keywords(chunk: &Chunk, offset: usize, long: bool) -> usize {
+    let keyword_count = chunk.codes[offset] as usize;
+    let width = if long { 3 } else { 1 };
+    for i in 0..keyword_count {
+        let at = offset + 1 + width * i;
+        let constant = if long {
+            read_long(&chunk.codes, at)
+        } else {
+            chunk.codes[at] as usize
+        };
+        print!(" {}:", chunk.constants.values[constant].as_string());
+    }
+    print!("\n");
+    offset + 1 + width * keyword_count
+}
+
+fn closure_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
+    let (constant, _) = constant_operand(chunk, offset);
+    let mut offset = constant_instruction(name, chunk, offset);
+    let Some(Obj::function(function)) = chunk.constants.values[constant].as_obj() else {
+        return offset;
+    };
+    for _ in 0..function.upvalue_count {
+        let is_local = chunk.codes[offset];
+        let index = (chunk.codes[offset + 1] as u16) << 8 | chunk.codes[offset + 2] as u16;
+        let kind = if is_local == 1 { "local" } else { "upvalue" };
+        print!(
+            "{:04}    |                     {} {}\n",
+            offset, kind, index
+        );
+        offset += 3;
+    }
+    offset
+}
+
 fn constant_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize {
-    let constant = chunk.codes[(offset + 1) as usize];
-    print!("{:<16} {:4} '", name, constant);
-    print_value(chunk.constants.values[constant as usize].clone());
-    print!("'\n");
-    offset + 2
+    let (constant, next) = constant_operand(chunk, offset);
+    print!(
+        "{:<16} {:4} '{}'\n",
+        name,
+        constant,
+        chunk.constants.values[constant].repr()
+    );
+    next
+}
+
+/// the constant index of the instruction at `offset` and the offset after it
+fn constant_operand(chunk: &Chunk, offset: usize) -> (usize, usize) {
+    match OpCode::try_from(chunk.codes[offset]) {
+        Ok(instruction) if instruction.is_long() => {
+            (read_long(&chunk.codes, offset + 1), offset + 4)
+        }
+        _ => (chunk.codes[offset + 1] as usize, offset + 2),
+    }
 }
@@ -0,0 +1,151 @@
+//! compile-time evaluation of operators on literals.
+//!
+//! the rules mirror the VM's: ints stay ints through checked arithmetic, mixed operands
+//! become floats with IEEE results, and anything the VM would raise an error for is left
+//! for it to raise at runtime, on the line it was written.
+
+use crate::chunk::{read_long, Chunk, OpCode};
+use crate::value::{values_equal, Value};
+use std::cmp::Ordering;
+
+/// the value of the code from `start` to the end of the chunk, when it only pushes literals
+/// and combines them with operators that can't fail on them
+pub(crate) fn fold(chunk: &Chunk, start: usize) -> Option<Value> {
+    let codes = &chunk.codes;
+    let mut stack: Vec<Value> = vec![];
+    let mut offset = start;
+    while offset < codes.len() {
+        let op = OpCode::try_from(codes[offset]).ok()?;
+        offset += 1;
+        let value = match op {
+            OpCode::OP_CONSTANT => {
+                offset += 1;
+                chunk.constants.values[codes[offset - 1] as usize].clone()
+            }
+            OpCode::OP_CONSTANT_LONG => {
+                offset += 3;
+                chunk.constants.values[read_long(codes, offset - 3)].clone()
+            }
+            OpCode::OP_NIL => Value::nil_val(),
+            OpCode::OP_TRUE => Value::bool_val(true),
+            OpCode::OP_FALSE => Value::bool_val(false),
+            OpCode::OP_NOT => {
+                let value = stack.pop()?;
+                Value::bool_val(value.is_nil() || (value.is_bool() && !value.as_bool()))
+            }
+            OpCode::OP_NEGATE => negate(stack.pop()?)?,
+            OpCode::OP_BIT_NOT => {
+                let value = stack.pop()?;
+                if !value.is_int() {
+                    return None;
+                }
+                Value::int_val(!value.as_int())
+            }
+            OpCode::OP_EQUAL
+            | OpCode::OP_GREATER
+            | OpCode::OP_LESS
+            | OpCode::OP_ADD
+            | OpCode::OP_SUBTRACT
+            | OpCode::OP_MULTIPLY
+            | OpCode::OP_DIVIDE
+            | OpCode::OP_MODULO
+            | OpCode::OP_BIT_AND
+            | OpCode::OP_BIT_OR
+            | OpCode::OP_BIT_XOR
+            | OpCode::OP_SHIFT_LEFT
+            | OpCode::OP_SHIFT_RIGHT => {
+                let b = stack.pop()?;
+                let a = stack.pop()?;
+                binary(op, a, b)?
+            }
+            _ => return None,
+        };
+        stack.push(value);
+    }
+    if stack.len() != 1 {
+        return None;
+    }
+    stack.pop()
+}
+
+fn negate(value: Value) -> Option<Value> {
+    if value.is_int() {
+        return Some(Value::int_val(value.as_int().checked_neg()?));
+    }
+    if !value.is_number() {
+        return None;
+    }
+    Some(Value::number_val(-value.as_number()))
+}
+
+fn binary(op: OpCode, a: Value, b: Value) -> Option<Value> {
+    let value = match op {
+        OpCode::OP_EQUAL => Value::bool_val(values_equal(&a, &b)),
+        OpCode::OP_ADD if a.is_string() && b.is_string() => {
+            Value::string_val(format!("{}{}", a.as_string(), b.as_string()))
+        }
+        OpCode::OP_ADD => arithmetic(a, b, i64::checked_add, |a, b| a + b)?,
+        OpCode::OP_SUBTRACT => arithmetic(a, b, i64::checked_sub, |a, b| a - b)?,
+        OpCode::OP_MULTIPLY => arithmetic(a, b, i64::checked_mul, |a, b| a * b)?,
+        // integer division by zero is a runtime error, checked_div leaves it unfolded
+        OpCode::OP_DIVIDE => arithmetic(a, b, i64::checked_div, |a, b| a / b)?,
+        OpCode::OP_MODULO => arithmetic(a, b, i64::checked_rem, |a, b| a % b)?,
+        OpCode::OP_GREATER | OpCode::OP_LESS => {
+            if !a.is_number() || !b.is_number() {
+                return None;
+            }
+            // no ordering when either side is NaN, so both comparisons are false
+            let ordering = if a.is_int() && b.is_int() {
+                a.as_int().partial_cmp(&b.as_int())
+            } else {
+                a.as_number().partial_cmp(&b.as_number())
+            };
+            let expected = if op == OpCode::OP_GREATER {
+                Ordering::Greater
+            } else {
+                Ordering::Less
+            };
+            Value::bool_val(ordering == Some(expected))
+        }
+        OpCode::OP_BIT_AND | OpCode::OP_BIT_OR | OpCode::OP_BIT_XOR => {
+            if !a.is_int() || !b.is_int() {
+                return None;
+            }
+            let (a, b) = (a.as_int(), b.as_int());
+            Value::int_val(match op {
+                OpCode::OP_BIT_AND => a & b,
+                OpCode::OP_BIT_OR => a | b,
+                _ => a ^ b,
+            })
+        }
+        OpCode::OP_SHIFT_LEFT | OpCode::OP_SHIFT_RIGHT => {
+            if !a.is_int() || !b.is_int() {
+                return None;
+            }
+            let shift = u32::try_from(b.as_int()).ok()?;
+            Value::int_val(if op == OpCode::OP_SHIFT_LEFT {
+                a.as_int().checked_shl(shift)?
+            } else {
+                a.as_int().checked_shr(shift)?
+            })
+        }
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// two ints through the checked `int_op`, any other pair of numbers as floats
+fn arithmetic(
+    a: Value,
+    b: Value,
+    int_op: fn(i64, i64) -> Option<i64>,
+    float_op: fn(f64, f64) -> f64,
+) -> Option<Value> {
+    if !a.is_number() || !b.is_number() {
+        return None;
+    }
+    if a.is_int() && b.is_int() {
+        return Some(Value::int_val(int_op(a.as_int(), b.as_int())?));
+    }
+    Some(Value::number_val(float_op(a.as_number(), b.as_number())))
+}
@@ -0,0 +1,25 @@
+//! a bytecode interpreter for Lox, `VM` is the embedding entry point
+#![allow(non_camel_case_types)]
+#![allow(
+    clippy::needless_return,
+    clippy::print_with_newline,
+    clippy::let_and_return,
+    clippy::collapsible_match,
+    clippy::expect_fun_call
+)]
+
+pub mod chunk;
+mod compiler;
+mod debug;
+mod fold;
+pub mod object;
+mod optimize;
+mod scanner;
+pub mod serialize;
+pub mod value;
+mod verify;
+pub mod vm;
+
+pub use object::{Getter, HostInstance, LoxClass, Method};
+pub use value::Value;
+pub use vm::{InterpretResult, LoxError, VMConfig, VM};
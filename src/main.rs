@@ -1,50 +1,100 @@
-pub mod chunk;
-mod compiler;
-mod debug;
-mod object;
-mod scanner;
-mod value;
-mod vm;
-
-use crate::chunk::OpCode::{OP_ADD, OP_CONSTANT, OP_DIVIDE, OP_NEGATE, OP_RETURN};
-use crate::vm::InterpretResult::{INTERPRET_COMPILE_ERROR, INTERPRET_RUNTIME_ERROR};
-use crate::vm::VM;
-use chunk::*;
-use debug::*;
-use std::cmp::PartialEq;
-use std::sync::atomic::Ordering;
-use value::*;
+#![allow(clippy::print_with_newline)]
+
+use r_lox_bytecode::chunk::Chunk;
+use r_lox_bytecode::serialize::is_compiled;
+use r_lox_bytecode::InterpretResult::{INTERPRET_COMPILE_ERROR, INTERPRET_RUNTIME_ERROR};
+use r_lox_bytecode::{VMConfig, VM};
+use std::path::{Path, PathBuf};
 
 fn main() {
-    let argc = std::env::args().into_iter().collect::<Vec<String>>();
-    let mut vm: VM = VM::new(Chunk::new());
-    if argc.len() == 1 {
-        repl(&mut vm);
-    } else if argc.len() == 2 {
-        run_file(&mut vm, &argc[1]);
-    } else {
-        eprint!("Usage: clox [path]\n");
-        std::process::exit(64);
+    let mut argc = std::env::args().collect::<Vec<String>>();
+    let mut config = VMConfig::default();
+    if let Some(level) = argc.get(1).and_then(|arg| arg.strip_prefix("-O")) {
+        let Ok(level @ 0..=2) = level.parse::<u8>() else {
+            usage();
+        };
+        config.opt_level = level;
+        argc.remove(1);
+    }
+    let mut vm: VM = VM::new(config);
+    match &argc[1..] {
+        [] => repl(&mut vm),
+        [flag, path] if flag == "--compile" => {
+            compile_file(&mut vm, path, &Path::new(path).with_extension("loxc"))
+        }
+        [flag, path, o, out] if flag == "--compile" && o == "-o" => {
+            compile_file(&mut vm, path, &PathBuf::from(out))
+        }
+        [path] => run_file(&mut vm, path),
+        _ => usage(),
     }
 }
 
+fn usage() -> ! {
+    eprint!("Usage: clox [-O0|-O1|-O2] [path]\n       clox [-O0|-O1|-O2] --compile path [-o out.loxc]\n");
+    exit(64);
+}
+
 fn repl(vm: &mut VM) {
+    // doc comment lines wait for the declaration they document
+    let mut doc = String::new();
     loop {
         eprint!("> ");
         let mut line = String::new();
         match std::io::stdin().read_line(&mut line) {
             Ok(0) => return,
-            Ok(n) => {
-                vm.interpret(&line);
+            Ok(_) => {
+                if let Some(name) = line.trim().strip_prefix("help ") {
+                    help(vm, name.trim());
+                    continue;
+                }
+                if line.trim_start().starts_with("///") {
+                    doc.push_str(&line);
+                    continue;
+                }
+                vm.interpret_line(&(std::mem::take(&mut doc) + &line));
             }
-            Err(e) => return,
+            Err(_) => return,
         }
     }
 }
 
+/// print the doc comment of a global, `help name` in the REPL
+fn help(vm: &VM, name: &str) {
+    match vm.doc(name) {
+        Some(doc) => print!("{}\n", doc),
+        None => print!("No documentation for '{}'.\n", name),
+    }
+}
+
+fn read_file(path: &str) -> Vec<u8> {
+    match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(_) => {
+            eprint!("Could not open file \"{}\".\n", path);
+            exit(74);
+        }
+    }
+}
+
+/// `path` holds either source code or a script compiled with `--compile`
 fn run_file(vm: &mut VM, path: &str) {
-    let source = std::fs::read_to_string(path).unwrap();
-    let result = vm.interpret(&source);
+    let bytes = read_file(path);
+    let result = if is_compiled(&bytes) {
+        match Chunk::deserialize(&bytes) {
+            Ok(chunk) => vm.interpret_chunk(chunk, Path::new(path)),
+            Err(message) => {
+                eprint!("{}: {}\n", path, message);
+                exit(65);
+            }
+        }
+    } else {
+        let Ok(source) = String::from_utf8(bytes) else {
+            eprint!("{}: not valid UTF-8.\n", path);
+            exit(65);
+        };
+        vm.interpret_file(&source, Path::new(path))
+    };
 
     if result == INTERPRET_COMPILE_ERROR {
         exit(65);
@@ -54,6 +104,28 @@ fn run_file(vm: &mut VM, path: &str) {
     }
 }
 
-fn exit(code: i32) {
+/// compile the script at `path` and save it to `out` without running it
+fn compile_file(vm: &mut VM, path: &str, out: &Path) {
+    let Ok(source) = String::from_utf8(read_file(path)) else {
+        eprint!("{}: not valid UTF-8.\n", path);
+        exit(65);
+    };
+    let Ok(chunk) = vm.compile(&source) else {
+        exit(65);
+    };
+    let bytes = match chunk.serialize() {
+        Ok(bytes) => bytes,
+        Err(message) => {
+            eprint!("{}: {}\n", path, message);
+            exit(65);
+        }
+    };
+    if std::fs::write(out, bytes).is_err() {
+        eprint!("Could not write file \"{}\".\n", out.display());
+        exit(74);
+    }
+}
+
+fn exit(code: i32) -> ! {
     std::process::exit(code);
 }
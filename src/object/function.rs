@@ -0,0 +1,83 @@
+use crate::chunk::Chunk;
+use crate::object::Module;
+use crate::value::Value;
+use std::cell::RefCell;
+use std::fmt::{Display, Formatter};
+use std::rc::Rc;
+
+/// a compiled function: its bytecode and what the VM needs to call it
+#[derive(Debug, Clone)]
+pub struct Function {
+    /// number of named parameters, the rest parameter not included
+    pub arity: usize,
+    /// defaults of the trailing optional parameters
+    pub defaults: Vec<Value>,
+    /// the last parameter collects extra positional arguments into a list
+    pub is_variadic: bool,
+    /// names of the named parameters, for keyword arguments
+    pub params: Vec<String>,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    /// None for the top-level script
+    pub name: Option<String>,
+    /// the `///` comment in front of the declaration
+    pub doc: Option<String>,
+}
+
+/// a function together with the variables it captured, the only callable value at runtime
+#[derive(Debug)]
+pub struct Closure {
+    pub function: Rc<Function>,
+    /// the module whose globals the function reads and writes
+    pub module: Rc<Module>,
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+/// a captured variable. It points at a stack slot while that slot is live and holds the
+/// value itself once the slot has been popped
+#[derive(Debug, Clone)]
+pub enum Upvalue {
+    open(usize),
+    closed(Value),
+}
+
+impl Function {
+    pub fn new(name: Option<String>) -> Self {
+        Self {
+            arity: 0,
+            defaults: vec![],
+            is_variadic: false,
+            params: vec![],
+            upvalue_count: 0,
+            chunk: Chunk::new(),
+            name,
+            doc: None,
+        }
+    }
+}
+
+impl Function {
+    /// number of arguments that must be given
+    pub fn required(&self) -> usize {
+        self.arity - self.defaults.len()
+    }
+}
+
+impl Closure {
+    pub fn new(function: Rc<Function>, module: Rc<Module>) -> Self {
+        Self {
+            function,
+            module,
+            upvalues: vec![],
+        }
+    }
+}
+
+impl Display for Function {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<fn {}>", name),
+            None => write!(f, "<script>"),
+        }
+    }
+}
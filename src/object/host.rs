@@ -0,0 +1,138 @@
+use crate::value::Value;
+use std::any::Any;
+use std::cell::{Ref, RefCell, RefMut};
+use std::fmt;
+use std::fmt::{Debug, Display};
+
+/// a method of a host class, an `Err` becomes a runtime error
+pub type Method<T> = fn(&mut T, &[Value]) -> Result<Value, String>;
+
+/// a read-only property of a host class
+pub type Getter<T> = fn(&T) -> Value;
+
+/// a Rust type scripts can construct and use like a class, see `VM::register_class`
+pub trait LoxClass: Sized + 'static {
+    /// the global the class is bound to
+    const NAME: &'static str;
+
+    /// called with the arguments of `Name(...)`
+    fn construct(args: &[Value]) -> Result<Self, String>;
+
+    fn methods() -> Vec<(&'static str, Method<Self>)> {
+        vec![]
+    }
+
+    fn properties() -> Vec<(&'static str, Getter<Self>)> {
+        vec![]
+    }
+}
+
+/// a `LoxClass` with its type erased, what an instance holds
+pub(crate) trait HostObject {
+    fn get_property(&self, name: &str) -> Option<Value>;
+    /// `None` when there is no such method
+    fn invoke(&mut self, name: &str, args: &[Value]) -> Option<Result<Value, String>>;
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: LoxClass> HostObject for T {
+    fn get_property(&self, name: &str) -> Option<Value> {
+        let (_, getter) = T::properties().into_iter().find(|(n, _)| *n == name)?;
+        Some(getter(self))
+    }
+
+    fn invoke(&mut self, name: &str, args: &[Value]) -> Option<Result<Value, String>> {
+        let (_, method) = T::methods().into_iter().find(|(n, _)| *n == name)?;
+        Some(method(self, args))
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// `LoxClass::construct` with the class erased
+type Constructor = fn(&[Value]) -> Result<Box<dyn HostObject>, String>;
+
+pub struct HostClass {
+    pub name: &'static str,
+    construct: Constructor,
+}
+
+pub struct HostInstance {
+    pub class_name: &'static str,
+    object: RefCell<Box<dyn HostObject>>,
+}
+
+fn construct<T: LoxClass>(args: &[Value]) -> Result<Box<dyn HostObject>, String> {
+    Ok(Box::new(T::construct(args)?))
+}
+
+impl HostClass {
+    pub fn new<T: LoxClass>() -> Self {
+        Self {
+            name: T::NAME,
+            construct: construct::<T>,
+        }
+    }
+
+    pub(crate) fn instantiate(&self, args: &[Value]) -> Result<HostInstance, String> {
+        Ok(HostInstance {
+            class_name: self.name,
+            object: RefCell::new((self.construct)(args)?),
+        })
+    }
+}
+
+impl HostInstance {
+    /// the Rust value behind the instance, if it is a `T`. The receiver of a method is
+    /// borrowed while the method runs, so this is `None` for it, even when it is passed to
+    /// its own method as an argument: use the `&mut T` the method gets instead.
+    pub fn borrow<T: LoxClass>(&self) -> Option<Ref<'_, T>> {
+        let object = self.object.try_borrow().ok()?;
+        Ref::filter_map(object, |o| o.as_any().downcast_ref::<T>()).ok()
+    }
+
+    /// like `borrow`, `None` for the receiver of the method that is running
+    pub fn borrow_mut<T: LoxClass>(&self) -> Option<RefMut<'_, T>> {
+        let object = self.object.try_borrow_mut().ok()?;
+        RefMut::filter_map(object, |o| o.as_any_mut().downcast_mut::<T>()).ok()
+    }
+
+    pub(crate) fn get_property(&self, name: &str) -> Option<Value> {
+        self.object.borrow().get_property(name)
+    }
+
+    pub(crate) fn invoke(&self, name: &str, args: &[Value]) -> Option<Result<Value, String>> {
+        self.object.borrow_mut().invoke(name, args)
+    }
+}
+
+impl Display for HostClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<class {}>", self.name)
+    }
+}
+
+impl Display for HostInstance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<{} instance>", self.class_name)
+    }
+}
+
+impl Debug for HostClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl Debug for HostInstance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
@@ -1,32 +1,96 @@
-use crate::object::Obj::string;
+mod function;
+mod host;
+mod module;
 
-#[derive(Debug, Clone, PartialEq)]
+pub use function::{Closure, Function, Upvalue};
+pub use host::{Getter, HostClass, HostInstance, LoxClass, Method};
+pub use module::Module;
+
+use crate::value::Value;
+use std::cell::RefCell;
+use std::fmt;
+use std::fmt::Display;
+use std::rc::Rc;
+
+#[derive(Debug, Clone)]
 pub enum Obj {
     string(String),
+    function(Rc<Function>),
+    closure(Rc<Closure>),
+    list(Rc<RefCell<Vec<Value>>>),
+    module(Rc<Module>),
+    class(Rc<HostClass>),
+    instance(Rc<HostInstance>),
 }
 
 impl Obj {
     pub fn string(str: String) -> Self {
-        string(str)
+        Obj::string(str)
     }
 
-    pub fn print_obj(&self) {
+    /// list elements are always written in repr form, like in the REPL
+    pub(crate) fn write(
+        &self,
+        f: &mut fmt::Formatter,
+        repr: bool,
+        seen: &mut Vec<*const RefCell<Vec<Value>>>,
+    ) -> fmt::Result {
         match self {
-            string(v) => print!("{:?}", self),
+            Obj::string(s) if repr => write!(f, "{:?}", s),
+            Obj::string(s) => write!(f, "{}", s),
+            Obj::function(function) => write!(f, "{}", function),
+            Obj::closure(c) => write!(f, "{}", c.function),
+            Obj::list(items) => {
+                if seen.contains(&Rc::as_ptr(items)) {
+                    return write!(f, "[...]");
+                }
+                seen.push(Rc::as_ptr(items));
+                write!(f, "[")?;
+                for (i, item) in items.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    item.write(f, true, seen)?;
+                }
+                seen.pop();
+                write!(f, "]")
+            }
+            Obj::module(m) => write!(f, "{}", m),
+            Obj::class(c) => write!(f, "{}", c),
+            Obj::instance(i) => write!(f, "{}", i),
         }
     }
 
     pub fn is_string(&self) -> bool {
-        match self {
-            string(_) => true,
-            _ => false,
-        }
+        matches!(self, Obj::string(_))
     }
 
     pub fn string_val(&self) -> &str {
         match self {
-            string(v) => v,
+            Obj::string(v) => v,
             _ => "",
         }
     }
 }
+
+impl Display for Obj {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, false, &mut vec![])
+    }
+}
+
+/// strings compare by value, everything else by identity
+impl PartialEq for Obj {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Obj::string(a), Obj::string(b)) => a == b,
+            (Obj::function(a), Obj::function(b)) => Rc::ptr_eq(a, b),
+            (Obj::closure(a), Obj::closure(b)) => Rc::ptr_eq(a, b),
+            (Obj::list(a), Obj::list(b)) => Rc::ptr_eq(a, b),
+            (Obj::module(a), Obj::module(b)) => Rc::ptr_eq(a, b),
+            (Obj::class(a), Obj::class(b)) => Rc::ptr_eq(a, b),
+            (Obj::instance(a), Obj::instance(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use crate::value::Value;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::rc::Rc;
+
+/// a loaded source file: its own global namespace and the names it lets importers see
+#[derive(Debug)]
+pub struct Module {
+    pub name: String,
+    /// canonical path of the source file, empty for the REPL
+    pub path: PathBuf,
+    /// shared when a VM moves its main module to another path, see `VM::interpret_file`
+    pub globals: Rc<RefCell<HashMap<String, Value>>>,
+    pub exports: HashSet<String>,
+}
+
+impl Module {
+    pub fn new(name: String, path: PathBuf, exports: HashSet<String>) -> Self {
+        Self {
+            name,
+            path,
+            globals: Rc::new(RefCell::new(HashMap::new())),
+            exports,
+        }
+    }
+
+    /// the value of an exported global
+    pub fn export(&self, name: &str) -> Option<Value> {
+        if !self.exports.contains(name) {
+            return None;
+        }
+        self.globals.borrow().get(name).cloned()
+    }
+}
+
+impl Display for Module {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<module {}>", self.name)
+    }
+}
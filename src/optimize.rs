@@ -0,0 +1,584 @@
+//! peephole optimization of a compiled function's bytecode.
+//!
+//! the code is decoded into a list of instructions whose jumps name the instruction they
+//! land on, rewritten until no rule applies, then encoded again, which recomputes every
+//! jump offset and the line table.
+//!
+//! level 1 applies the peephole rules, level 2 also fuses hot sequences into
+//! superinstructions.
+
+use crate::chunk::{read_long, Chunk, OpCode};
+use crate::object::Obj;
+
+struct Instruction {
+    op: OpCode,
+    /// the operand bytes, except for jump offsets which are derived from `targets`
+    operands: Vec<u8>,
+    /// the indices of the instructions a jump lands on
+    targets: Vec<usize>,
+    line: usize,
+}
+
+/// fails, leaving the chunk as it was, when a jump ends up too long for its offset
+pub(crate) fn optimize(chunk: &mut Chunk, level: u8) -> Result<(), String> {
+    let mut code = decode(chunk);
+    loop {
+        let mut changed = thread_jumps(&mut code);
+        changed |= fuse_comparisons(&mut code);
+        changed |= drop_discarded_pushes(&mut code);
+        changed |= drop_unreachable(&mut code);
+        changed |= drop_empty_jumps(&mut code);
+        if level >= 2 {
+            changed |= fuse_superinstructions(&mut code, chunk);
+        }
+        if !changed {
+            break;
+        }
+    }
+    return encode(chunk, &code);
+}
+
+fn decode(chunk: &Chunk) -> Vec<Instruction> {
+    let codes = &chunk.codes;
+    let mut code = vec![];
+    // the instruction starting at each offset and the target offsets of each instruction
+    let mut index = vec![usize::MAX; codes.len() + 1];
+    let mut target_offsets = vec![];
+    let mut offset = 0;
+    while offset < codes.len() {
+        let op = OpCode::try_from(codes[offset]).expect("the compiler emits valid opcodes");
+        let start = offset + 1;
+        let end = start + operand_len(chunk, offset, op);
+        let mut targets = vec![];
+        let next = match op {
+            OpCode::OP_JUMP
+            | OpCode::OP_JUMP_IF_FALSE
+            | OpCode::OP_JUMP_IF_NOT_NIL
+            | OpCode::OP_LESS_LOCALS_JUMP => {
+                targets.push(end + 2 + read_short(codes, end));
+                end + 2
+            }
+            OpCode::OP_LOOP => {
+                targets.push(end + 2 - read_short(codes, end));
+                end + 2
+            }
+            OpCode::OP_JUMP_TABLE => {
+                let entries = codes[offset + 3] as usize + 1;
+                let table_end = end + 2 * entries;
+                for entry in 0..entries {
+                    targets.push(table_end - read_short(codes, end + 2 * entry));
+                }
+                table_end
+            }
+            _ => end,
+        };
+        index[offset] = code.len();
+        target_offsets.push(targets);
+        code.push(Instruction {
+            op,
+            operands: codes[start..end].to_vec(),
+            targets: vec![],
+            line: chunk.get_line(offset),
+        });
+        offset = next;
+    }
+    index[codes.len()] = code.len();
+    for (instruction, targets) in code.iter_mut().zip(target_offsets) {
+        instruction.targets = targets.into_iter().map(|target| index[target]).collect();
+    }
+    return code;
+}
+
+fn read_short(codes: &[u8], offset: usize) -> usize {
+    return (codes[offset] as usize) << 8 | codes[offset + 1] as usize;
+}
+
+/// the number of operand bytes after the opcode at `offset`, not counting jump offsets
+fn operand_len(chunk: &Chunk, offset: usize, op: OpCode) -> usize {
+    let codes = &chunk.codes;
+    let index = if op.is_long() { 3 } else { 1 };
+    match op {
+        OpCode::OP_NIL
+        | OpCode::OP_TRUE
+        | OpCode::OP_FALSE
+        | OpCode::OP_POP
+        | OpCode::OP_DUP
+        | OpCode::OP_EQUAL
+        | OpCode::OP_NOT_EQUAL
+        | OpCode::OP_GREATER
+        | OpCode::OP_GREATER_EQUAL
+        | OpCode::OP_LESS
+        | OpCode::OP_LESS_EQUAL
+        | OpCode::OP_ADD
+        | OpCode::OP_SUBTRACT
+        | OpCode::OP_MULTIPLY
+        | OpCode::OP_DIVIDE
+        | OpCode::OP_MODULO
+        | OpCode::OP_BIT_AND
+        | OpCode::OP_BIT_OR
+        | OpCode::OP_BIT_XOR
+        | OpCode::OP_BIT_NOT
+        | OpCode::OP_SHIFT_LEFT
+        | OpCode::OP_SHIFT_RIGHT
+        | OpCode::OP_NOT
+        | OpCode::OP_NEGATE
+        | OpCode::OP_PRINT
+        | OpCode::OP_ECHO
+        | OpCode::OP_GET_INDEX
+        | OpCode::OP_CLOSE_UPVALUE
+        | OpCode::OP_RETURN
+        | OpCode::OP_JUMP
+        | OpCode::OP_JUMP_IF_FALSE
+        | OpCode::OP_JUMP_IF_NOT_NIL
+        | OpCode::OP_LOOP => 0,
+        OpCode::OP_GET_UPVALUE
+        | OpCode::OP_SET_UPVALUE
+        | OpCode::OP_CALL
+        | OpCode::OP_INC_LOCAL
+        | OpCode::OP_MATCH_LIST => 1,
+        OpCode::OP_ADD_LOCAL_CONST => 2,
+        // the two slots, the jump offset comes after
+        OpCode::OP_LESS_LOCALS_JUMP => 2,
+        // the minimum and the entry count, the entries are jump offsets
+        OpCode::OP_JUMP_TABLE => 3,
+        OpCode::OP_CONSTANT
+        | OpCode::OP_CONSTANT_LONG
+        | OpCode::OP_GET_LOCAL
+        | OpCode::OP_GET_LOCAL_LONG
+        | OpCode::OP_SET_LOCAL
+        | OpCode::OP_SET_LOCAL_LONG
+        | OpCode::OP_GET_GLOBAL
+        | OpCode::OP_GET_GLOBAL_LONG
+        | OpCode::OP_DEFINE_GLOBAL
+        | OpCode::OP_DEFINE_GLOBAL_LONG
+        | OpCode::OP_SET_GLOBAL
+        | OpCode::OP_SET_GLOBAL_LONG
+        | OpCode::OP_GET_PROPERTY
+        | OpCode::OP_GET_PROPERTY_LONG
+        | OpCode::OP_IMPORT
+        | OpCode::OP_IMPORT_LONG => index,
+        OpCode::OP_INVOKE | OpCode::OP_INVOKE_LONG => index + 1,
+        OpCode::OP_CALL_KW | OpCode::OP_CALL_KW_LONG => 2 + index * codes[offset + 2] as usize,
+        OpCode::OP_INVOKE_KW | OpCode::OP_INVOKE_KW_LONG => {
+            index + 2 + index * codes[offset + index + 2] as usize
+        }
+        OpCode::OP_CLOSURE | OpCode::OP_CLOSURE_LONG => {
+            let constant = if op.is_long() {
+                read_long(codes, offset + 1)
+            } else {
+                codes[offset + 1] as usize
+            };
+            let Some(Obj::function(function)) = chunk.constants.values[constant].as_obj() else {
+                unreachable!("the closure operand is a function");
+            };
+            index + 3 * function.upvalue_count
+        }
+    }
+}
+
+/// the byte offset of each instruction, with one more entry for the end of the code. A jump
+/// is the same size whatever its distance, so they don't depend on where jumps land
+fn offsets(code: &[Instruction]) -> Vec<usize> {
+    let mut offsets = vec![0];
+    for instruction in code {
+        let size = 1 + instruction.operands.len() + 2 * instruction.targets.len();
+        offsets.push(offsets.last().unwrap() + size);
+    }
+    return offsets;
+}
+
+fn encode(chunk: &mut Chunk, code: &[Instruction]) -> Result<(), String> {
+    let offsets = offsets(code);
+    let mut optimized = Chunk::new();
+    for (i, instruction) in code.iter().enumerate() {
+        let line = instruction.line;
+        optimized.write_chunk(instruction.op, line);
+        for byte in &instruction.operands {
+            optimized.write_chunk(*byte, line);
+        }
+        // jumps count from the end of the instruction, forward unless they loop back
+        let end = offsets[i + 1];
+        for target in &instruction.targets {
+            let jump = match instruction.op {
+                OpCode::OP_LOOP | OpCode::OP_JUMP_TABLE => end - offsets[*target],
+                _ => offsets[*target] - end,
+            };
+            if jump > u16::MAX as usize {
+                return Err("Too much code to jump over.".to_string());
+            }
+            optimized.write_chunk((jump >> 8) as u8, line);
+            optimized.write_chunk(jump as u8, line);
+        }
+    }
+    chunk.codes = optimized.codes;
+    chunk.lines = optimized.lines;
+    return Ok(());
+}
+
+/// which instructions some jump lands on, with one more entry for the end of the code
+fn jump_targets(code: &[Instruction]) -> Vec<bool> {
+    let mut targeted = vec![false; code.len() + 1];
+    for instruction in code {
+        for target in &instruction.targets {
+            targeted[*target] = true;
+        }
+    }
+    return targeted;
+}
+
+/// drop the instructions marked dead, jumps to one of them land on the next one kept
+fn remove(code: &mut Vec<Instruction>, dead: &[bool]) -> bool {
+    if !dead.contains(&true) {
+        return false;
+    }
+    let mut index = Vec::with_capacity(code.len() + 1);
+    let mut kept = 0;
+    for is_dead in dead {
+        index.push(kept);
+        kept += !is_dead as usize;
+    }
+    index.push(kept);
+    let mut dead = dead.iter();
+    code.retain(|_| !dead.next().unwrap());
+    for instruction in code.iter_mut() {
+        for target in instruction.targets.iter_mut() {
+            *target = index[*target];
+        }
+    }
+    return true;
+}
+
+/// a jump landing on a jump that is sure to be taken goes straight to its target. The
+/// conditional jumps don't pop, so one landing on the same kind of jump takes it too
+fn thread_jumps(code: &mut [Instruction]) -> bool {
+    let offsets = offsets(code);
+    let mut changed = false;
+    for i in 0..code.len() {
+        let op = code[i].op;
+        if !matches!(
+            op,
+            OpCode::OP_JUMP | OpCode::OP_JUMP_IF_FALSE | OpCode::OP_JUMP_IF_NOT_NIL
+        ) {
+            continue;
+        }
+        // these jumps only go forward, so the chain ends
+        let mut target = code[i].targets[0];
+        while let Some(next) = code.get(target) {
+            if next.op != OpCode::OP_JUMP && next.op != op {
+                break;
+            }
+            // skipping a hop makes the jump longer, it has to stay within its 16-bit offset
+            if offsets[next.targets[0]] - offsets[i + 1] > u16::MAX as usize {
+                break;
+            }
+            target = next.targets[0];
+        }
+        if target != code[i].targets[0] {
+            code[i].targets[0] = target;
+            changed = true;
+        }
+    }
+    return changed;
+}
+
+/// `!=`, `>=` and `<=` compile to a comparison and an `OP_NOT`
+fn fuse_comparisons(code: &mut Vec<Instruction>) -> bool {
+    let targeted = jump_targets(code);
+    let mut dead = vec![false; code.len()];
+    for i in 1..code.len() {
+        if code[i].op != OpCode::OP_NOT || targeted[i] {
+            continue;
+        }
+        code[i - 1].op = match code[i - 1].op {
+            OpCode::OP_EQUAL => OpCode::OP_NOT_EQUAL,
+            OpCode::OP_LESS => OpCode::OP_GREATER_EQUAL,
+            OpCode::OP_GREATER => OpCode::OP_LESS_EQUAL,
+            _ => continue,
+        };
+        dead[i] = true;
+    }
+    return remove(code, &dead);
+}
+
+/// a value pushed without side effects and popped right away, like an expression
+/// statement of a literal or a variable
+fn drop_discarded_pushes(code: &mut Vec<Instruction>) -> bool {
+    let targeted = jump_targets(code);
+    let mut dead = vec![false; code.len()];
+    for i in 1..code.len() {
+        // another path into the pop has a different value to discard
+        if code[i].op != OpCode::OP_POP || targeted[i] || dead[i - 1] {
+            continue;
+        }
+        if matches!(
+            code[i - 1].op,
+            OpCode::OP_CONSTANT
+                | OpCode::OP_CONSTANT_LONG
+                | OpCode::OP_NIL
+                | OpCode::OP_TRUE
+                | OpCode::OP_FALSE
+                | OpCode::OP_DUP
+                | OpCode::OP_GET_LOCAL
+                | OpCode::OP_GET_LOCAL_LONG
+                | OpCode::OP_GET_UPVALUE
+        ) {
+            dead[i - 1] = true;
+            dead[i] = true;
+        }
+    }
+    return remove(code, &dead);
+}
+
+/// code no path from the start of the function reaches, like what follows a `return`
+fn drop_unreachable(code: &mut Vec<Instruction>) -> bool {
+    let mut reached = vec![false; code.len()];
+    let mut pending = vec![0];
+    while let Some(i) = pending.pop() {
+        if i >= code.len() || reached[i] {
+            continue;
+        }
+        reached[i] = true;
+        pending.extend(&code[i].targets);
+        if !matches!(
+            code[i].op,
+            OpCode::OP_RETURN | OpCode::OP_JUMP | OpCode::OP_LOOP | OpCode::OP_JUMP_TABLE
+        ) {
+            pending.push(i + 1);
+        }
+    }
+    let dead: Vec<bool> = reached.iter().map(|reached| !reached).collect();
+    return remove(code, &dead);
+}
+
+/// jumps to the instruction right after them, left behind by the other rules
+fn drop_empty_jumps(code: &mut Vec<Instruction>) -> bool {
+    let dead: Vec<bool> = code
+        .iter()
+        .enumerate()
+        .map(|(i, instruction)| {
+            matches!(
+                instruction.op,
+                OpCode::OP_JUMP | OpCode::OP_JUMP_IF_FALSE | OpCode::OP_JUMP_IF_NOT_NIL
+            ) && instruction.targets[0] == i + 1
+        })
+        .collect();
+    return remove(code, &dead);
+}
+
+/// replace the sequences the superinstructions stand for. Only the first instruction of
+/// a sequence may be a jump target
+fn fuse_superinstructions(code: &mut Vec<Instruction>, chunk: &Chunk) -> bool {
+    let targeted = jump_targets(code);
+    let mut dead = vec![false; code.len()];
+    let mut i = 0;
+    while i < code.len() {
+        match superinstruction(code, chunk, i) {
+            Some((len, fused)) if !targeted[i + 1..i + len].contains(&true) => {
+                code[i] = fused;
+                dead[i + 1..i + len].fill(true);
+                i += len;
+            }
+            _ => i += 1,
+        }
+    }
+    return remove(code, &dead);
+}
+
+/// the superinstruction for the code from `i` on and the number of instructions it
+/// replaces, trying the longer sequences first
+fn superinstruction(code: &[Instruction], chunk: &Chunk, i: usize) -> Option<(usize, Instruction)> {
+    use OpCode::{
+        OP_ADD, OP_CONSTANT, OP_DUP, OP_GET_LOCAL, OP_JUMP_IF_FALSE, OP_LESS, OP_POP, OP_SET_LOCAL,
+    };
+    let ops: Vec<OpCode> = code[i..]
+        .iter()
+        .take(7)
+        .map(|instruction| instruction.op)
+        .collect();
+    let operands = |at: usize| code[i + at].operands.clone();
+    let is_one = |at: usize| {
+        let value = &chunk.constants.values[code[i + at].operands[0] as usize];
+        value.is_int() && value.as_int() == 1
+    };
+    // errors are raised by the operator, so the fused instruction takes its line
+    let fused = |op, operands, targets, at: usize| Instruction {
+        op,
+        operands,
+        targets,
+        line: code[i + at].line,
+    };
+    let replacement = match ops.as_slice() {
+        // `slot++;`, which leaves the old value underneath for the statement to pop
+        [OP_GET_LOCAL, OP_DUP, OP_CONSTANT, OP_ADD, OP_SET_LOCAL, OP_POP, OP_POP, ..]
+            if operands(0) == operands(4) && is_one(2) =>
+        {
+            (7, fused(OpCode::OP_INC_LOCAL, operands(0), vec![], 3))
+        }
+        // `slot += 1;` and `++slot;`
+        [OP_GET_LOCAL, OP_CONSTANT, OP_ADD, OP_SET_LOCAL, OP_POP, ..]
+            if operands(0) == operands(3) && is_one(1) =>
+        {
+            (5, fused(OpCode::OP_INC_LOCAL, operands(0), vec![], 2))
+        }
+        // the condition of an `if`, whose branches both start by popping it
+        [OP_GET_LOCAL, OP_GET_LOCAL, OP_LESS, OP_JUMP_IF_FALSE, OP_POP, ..]
+            if code[code[i + 3].targets[0]].op == OP_POP =>
+        {
+            let slots = [operands(0), operands(1)].concat();
+            let target = code[i + 3].targets[0] + 1;
+            (
+                5,
+                fused(OpCode::OP_LESS_LOCALS_JUMP, slots, vec![target], 2),
+            )
+        }
+        [OP_GET_LOCAL, OP_CONSTANT, OP_ADD, ..] => {
+            let slot_and_constant = [operands(0), operands(1)].concat();
+            (
+                3,
+                fused(OpCode::OP_ADD_LOCAL_CONST, slot_and_constant, vec![], 2),
+            )
+        }
+        _ => return None,
+    };
+    return Some(replacement);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::{VMConfig, VM};
+    use std::cell::RefCell;
+    use std::io::Write;
+    use std::rc::Rc;
+
+    /// a writer whose bytes stay readable after the VM takes it
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            return Ok(buf.len());
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    /// what running `source` at `opt_level` prints, errors included
+    fn run(source: &str, opt_level: u8) -> String {
+        let output = Output::default();
+        let mut vm = VM::new(VMConfig {
+            out: Box::new(output.clone()),
+            err: Box::new(output.clone()),
+            opt_level,
+            ..VMConfig::default()
+        });
+        vm.interpret(source);
+        let bytes = output.0.borrow().clone();
+        return String::from_utf8(bytes).unwrap();
+    }
+
+    fn assert_same_at_every_level(source: &str) {
+        let expected = run(source, 0);
+        assert!(!expected.contains("Error at"), "{}", expected);
+        for opt_level in 1..=2 {
+            assert_eq!(run(source, opt_level), expected, "at -O{}", opt_level);
+        }
+    }
+
+    #[test]
+    fn branches_behave_the_same() {
+        assert_same_at_every_level(
+            "
+            fun classify(n) {
+                if (n < 0) return \"negative\";
+                else if (n == 0) return \"zero\";
+                if (!(n >= 10)) {
+                    if (n != 5) return \"small\";
+                }
+                return n > 100 ? \"huge\" : n <= 10 ? \"edge\" : \"big\";
+            }
+            print classify(-3);
+            print classify(0);
+            print classify(5);
+            print classify(7);
+            print classify(10);
+            print classify(50);
+            print classify(1000);
+            ",
+        );
+    }
+
+    #[test]
+    fn loops_behave_the_same() {
+        assert_same_at_every_level(
+            "
+            fun sum(n, acc) {
+                if (n == 0) return acc;
+                return sum(n - 1, acc + n);
+            }
+            fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+            fun counter() {
+                var count = 0;
+                fun next() { count = count + 1; return count; }
+                return next;
+            }
+            fun repeat(f, times) {
+                if (times > 0) { f(); repeat(f, times - 1); }
+            }
+            print sum(50, 0);
+            print fib(15);
+            var next = counter();
+            repeat(next, 10);
+            print next();
+            {
+                var x = 1.5;
+                var y = x * 2 - 1;
+                print x < y ? x + 1 : y - 1;
+            }
+            ",
+        );
+    }
+
+    #[test]
+    fn match_behaves_the_same() {
+        assert_same_at_every_level(
+            "
+            fun name(n) {
+                match (n) {
+                    0 => return \"zero\";
+                    1 | 2 | 3 => return \"few\";
+                    10 => return \"ten\";
+                    \"s\" => return \"string\";
+                    _ => return \"many\";
+                }
+            }
+            fun names(n) {
+                if (n > 12) return;
+                print name(n);
+                names(n + 1);
+            }
+            names(0);
+            print name(\"s\");
+            match (true) { false => print \"no\"; true => print \"yes\"; }
+            match (7) { 1 => print \"one\"; }
+            ",
+        );
+    }
+
+    #[test]
+    fn runtime_errors_report_the_same_line() {
+        assert_same_at_every_level(
+            "
+            fun check(n) {
+                if (n > 2)
+                    return n + nil;
+                return check(n + 1);
+            }
+            print \"before\";
+            check(0);
+            ",
+        );
+    }
+}
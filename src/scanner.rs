@@ -1,14 +1,6 @@
-use crate::scanner::TokenType::{
-    TOKEN_AND, TOKEN_BANG, TOKEN_BANG_EQUAL, TOKEN_CLASS, TOKEN_COMMA, TOKEN_DOT, TOKEN_ELSE,
-    TOKEN_EOF, TOKEN_EQUAL, TOKEN_EQUAL_EQUAL, TOKEN_FALSE, TOKEN_FOR, TOKEN_FUN, TOKEN_GREATER,
-    TOKEN_GREATER_EQUAL, TOKEN_IDENTIFIER, TOKEN_IF, TOKEN_LEFT_BRACE, TOKEN_LEFT_PAREN,
-    TOKEN_LESS, TOKEN_LESS_EQUAL, TOKEN_MINUS, TOKEN_NIL, TOKEN_NUMBER, TOKEN_OR, TOKEN_PLUS,
-    TOKEN_PRINT, TOKEN_RETURN, TOKEN_RIGHT_BRACE, TOKEN_RIGHT_PAREN, TOKEN_SEMICOLON, TOKEN_SLASH,
-    TOKEN_STAR, TOKEN_STRING, TOKEN_SUPER, TOKEN_THIS, TOKEN_TRUE, TOKEN_VAR, TOKEN_WHILE,
-};
-use std::io::read_to_string;
-
-#[derive(Debug)]
+use crate::scanner::TokenType::*;
+
+#[derive(Debug, Clone)]
 pub struct Scanner {
     start: usize,
     current: usize,
@@ -30,6 +22,8 @@ pub enum TokenType {
     TOKEN_RIGHT_PAREN,
     TOKEN_LEFT_BRACE,
     TOKEN_RIGHT_BRACE,
+    TOKEN_LEFT_BRACKET,
+    TOKEN_RIGHT_BRACKET,
     TOKEN_COMMA,
     TOKEN_DOT,
     TOKEN_MINUS,
@@ -37,6 +31,12 @@ pub enum TokenType {
     TOKEN_SEMICOLON,
     TOKEN_SLASH,
     TOKEN_STAR,
+    TOKEN_PERCENT,
+    TOKEN_PIPE,
+    TOKEN_AMPERSAND,
+    TOKEN_CARET,
+    TOKEN_TILDE,
+    TOKEN_COLON,
     // One or two character tokens.
     TOKEN_BANG,
     TOKEN_BANG_EQUAL,
@@ -46,18 +46,39 @@ pub enum TokenType {
     TOKEN_GREATER_EQUAL,
     TOKEN_LESS,
     TOKEN_LESS_EQUAL,
+    TOKEN_LESS_LESS,
+    TOKEN_GREATER_GREATER,
+    TOKEN_ARROW,
+    TOKEN_MINUS_EQUAL,
+    TOKEN_MINUS_MINUS,
+    TOKEN_PLUS_EQUAL,
+    TOKEN_PLUS_PLUS,
+    TOKEN_SLASH_EQUAL,
+    TOKEN_STAR_EQUAL,
+    TOKEN_PERCENT_EQUAL,
+    TOKEN_QUESTION,
+    TOKEN_QUESTION_QUESTION,
+    TOKEN_QUESTION_DOT,
+    TOKEN_DOT_DOT_DOT,
     // Literals.
     TOKEN_IDENTIFIER,
     TOKEN_STRING,
     TOKEN_NUMBER,
+    TOKEN_INTEGER,
     // Keywords.
     TOKEN_AND,
+    TOKEN_AS,
     TOKEN_CLASS,
+    TOKEN_CONST,
     TOKEN_ELSE,
+    TOKEN_EXPORT,
     TOKEN_FALSE,
     TOKEN_FOR,
+    TOKEN_FROM,
     TOKEN_FUN,
     TOKEN_IF,
+    TOKEN_IMPORT,
+    TOKEN_MATCH,
     TOKEN_NIL,
     TOKEN_OR,
     TOKEN_PRINT,
@@ -68,6 +89,8 @@ pub enum TokenType {
     TOKEN_VAR,
     TOKEN_WHILE,
 
+    /// a `///` line, kept for documentation
+    TOKEN_DOC_COMMENT,
     TOKEN_ERROR,
     TOKEN_EOF,
 }
@@ -93,7 +116,9 @@ impl Scanner {
 
     /// get next token
     pub fn scan_token(&mut self) -> Token {
-        self.skip_whitespace();
+        if let Some(error) = self.skip_whitespace() {
+            return error;
+        }
         self.start = self.current;
         if self.is_at_end() {
             return self.make_token(TOKEN_EOF);
@@ -112,13 +137,84 @@ impl Scanner {
             ')' => return self.make_token(TOKEN_RIGHT_PAREN),
             '{' => return self.make_token(TOKEN_LEFT_BRACE),
             '}' => return self.make_token(TOKEN_RIGHT_BRACE),
+            '[' => return self.make_token(TOKEN_LEFT_BRACKET),
+            ']' => return self.make_token(TOKEN_RIGHT_BRACKET),
             ';' => return self.make_token(TOKEN_SEMICOLON),
             ',' => return self.make_token(TOKEN_COMMA),
-            '.' => return self.make_token(TOKEN_DOT),
-            '-' => return self.make_token(TOKEN_MINUS),
-            '+' => return self.make_token(TOKEN_PLUS),
-            '/' => return self.make_token(TOKEN_SLASH),
-            '*' => return self.make_token(TOKEN_STAR),
+            '.' => {
+                if self.peek() == '.' && self.peek_next() == '.' {
+                    self.advance();
+                    self.advance();
+                    return self.make_token(TOKEN_DOT_DOT_DOT);
+                }
+                return self.make_token(TOKEN_DOT);
+            }
+            '-' => {
+                let token = if self.match_('=') {
+                    TOKEN_MINUS_EQUAL
+                } else if self.match_('-') {
+                    TOKEN_MINUS_MINUS
+                } else {
+                    TOKEN_MINUS
+                };
+                return self.make_token(token);
+            }
+            '+' => {
+                let token = if self.match_('=') {
+                    TOKEN_PLUS_EQUAL
+                } else if self.match_('+') {
+                    TOKEN_PLUS_PLUS
+                } else {
+                    TOKEN_PLUS
+                };
+                return self.make_token(token);
+            }
+            '/' if self.match_('/') => {
+                // only doc comments are left by skip_whitespace
+                while self.peek() != '\n' && !self.is_at_end() {
+                    self.advance();
+                }
+                return self.make_token(TOKEN_DOC_COMMENT);
+            }
+            '/' => {
+                let token = if self.match_('=') {
+                    TOKEN_SLASH_EQUAL
+                } else {
+                    TOKEN_SLASH
+                };
+                return self.make_token(token);
+            }
+            ':' => return self.make_token(TOKEN_COLON),
+            '?' => {
+                let token = if self.match_('?') {
+                    TOKEN_QUESTION_QUESTION
+                } else if self.match_('.') {
+                    TOKEN_QUESTION_DOT
+                } else {
+                    TOKEN_QUESTION
+                };
+                return self.make_token(token);
+            }
+            '*' => {
+                let token = if self.match_('=') {
+                    TOKEN_STAR_EQUAL
+                } else {
+                    TOKEN_STAR
+                };
+                return self.make_token(token);
+            }
+            '%' => {
+                let token = if self.match_('=') {
+                    TOKEN_PERCENT_EQUAL
+                } else {
+                    TOKEN_PERCENT
+                };
+                return self.make_token(token);
+            }
+            '|' => return self.make_token(TOKEN_PIPE),
+            '&' => return self.make_token(TOKEN_AMPERSAND),
+            '^' => return self.make_token(TOKEN_CARET),
+            '~' => return self.make_token(TOKEN_TILDE),
             '!' => {
                 let token = if self.match_('=') {
                     TOKEN_BANG_EQUAL
@@ -130,6 +226,8 @@ impl Scanner {
             '=' => {
                 let token = if self.match_('=') {
                     TOKEN_EQUAL_EQUAL
+                } else if self.match_('>') {
+                    TOKEN_ARROW
                 } else {
                     TOKEN_EQUAL
                 };
@@ -138,6 +236,8 @@ impl Scanner {
             '<' => {
                 let token = if self.match_('=') {
                     TOKEN_LESS_EQUAL
+                } else if self.match_('<') {
+                    TOKEN_LESS_LESS
                 } else {
                     TOKEN_LESS
                 };
@@ -146,6 +246,8 @@ impl Scanner {
             '>' => {
                 let token = if self.match_('=') {
                     TOKEN_GREATER_EQUAL
+                } else if self.match_('>') {
+                    TOKEN_GREATER_GREATER
                 } else {
                     TOKEN_GREATER
                 };
@@ -159,7 +261,7 @@ impl Scanner {
     }
 
     fn is_at_end(&self) -> bool {
-        self.current == self.source.len() - 1
+        self.current >= self.source.len()
     }
 
     fn error_token(&mut self, message: String) -> Token {
@@ -172,13 +274,9 @@ impl Scanner {
     }
 
     fn make_token(&mut self, token_type: TokenType) -> Token {
-        println!(
-            "make_token: start: {}, current: {}",
-            self.start, self.current
-        );
         let token = Token {
             r#type: token_type,
-            lexume: String::from_iter(&self.source[self.start..self.current - self.start]),
+            lexume: String::from_iter(&self.source[self.start..self.current]),
             line: self.line,
         };
         token
@@ -201,7 +299,19 @@ impl Scanner {
         return true;
     }
 
-    fn skip_whitespace(&mut self) {
+    /// get next token, skipping doc comments. Used to look ahead.
+    pub fn scan_code_token(&mut self) -> Token {
+        loop {
+            let token = self.scan_token();
+            if token.r#type != TOKEN_DOC_COMMENT {
+                return token;
+            }
+        }
+    }
+
+    /// skip whitespace and comments other than doc comments, returning an error token for
+    /// an unterminated block comment
+    fn skip_whitespace(&mut self) -> Option<Token> {
         loop {
             let c = self.peek();
             match c {
@@ -214,39 +324,83 @@ impl Scanner {
                 }
                 '/' => {
                     if self.peek_next() == '/' {
+                        if self.is_doc_comment() {
+                            return None;
+                        }
                         // A comment goes until the end of the line.
                         while self.peek() != '\n' && !self.is_at_end() {
                             self.advance();
                         }
+                    } else if self.peek_next() == '*' {
+                        if let Some(error) = self.block_comment() {
+                            return Some(error);
+                        }
                     } else {
-                        return;
+                        return None;
                     }
                 }
-                _ => return,
+                _ => return None,
             };
         }
     }
 
+    /// `///` but not `////`
+    fn is_doc_comment(&self) -> bool {
+        let at = |offset: usize| self.source.get(self.current + offset).copied();
+        at(2) == Some('/') && at(3) != Some('/')
+    }
+
+    /// a `/* ... */` comment, which may contain nested block comments
+    fn block_comment(&mut self) -> Option<Token> {
+        let line = self.line;
+        self.advance();
+        self.advance();
+        let mut depth = 1;
+        while depth > 0 {
+            if self.is_at_end() {
+                return Some(Token {
+                    r#type: TOKEN_ERROR,
+                    lexume: "Unterminated block comment.".into(),
+                    line,
+                });
+            }
+            if self.peek() == '/' && self.peek_next() == '*' {
+                self.advance();
+                depth += 1;
+            } else if self.peek() == '*' && self.peek_next() == '/' {
+                self.advance();
+                depth -= 1;
+            } else if self.peek() == '\n' {
+                self.line += 1;
+            }
+            self.advance();
+        }
+        None
+    }
+
     fn peek(&self) -> char {
+        if self.is_at_end() {
+            return '\0';
+        }
         self.source[self.current]
     }
 
     fn peek_next(&self) -> char {
-        if self.is_at_end() {
+        if self.current + 1 >= self.source.len() {
             return '\0';
         }
         return self.source[self.current + 1];
     }
 
     fn string(&mut self) -> Token {
-        while (self.peek() != '"' && !self.is_at_end()) {
-            if (self.peek() == '\n') {
+        while self.peek() != '"' && !self.is_at_end() {
+            if self.peek() == '\n' {
                 self.line += 1;
             }
             self.advance();
         }
 
-        if (self.is_at_end()) {
+        if self.is_at_end() {
             return self.error_token("Unterminated string.".into());
         }
 
@@ -255,22 +409,97 @@ impl Scanner {
         return self.make_token(TOKEN_STRING);
     }
 
+    /// an integer, or a float when there is a fractional part or an exponent. Integers may
+    /// be written in hex, binary or octal with a `0x`, `0b` or `0o` prefix, and any digits
+    /// may be grouped with underscores: `1_000_000`
     fn number(&mut self) -> Token {
-        while is_digit(self.peek()) {
-            self.advance();
+        if self.source[self.start] == '0' {
+            let radix = match self.peek() {
+                'x' | 'X' => Some((16, "hex")),
+                'b' | 'B' => Some((2, "binary")),
+                'o' | 'O' => Some((8, "octal")),
+                _ => None,
+            };
+            if let Some((radix, name)) = radix {
+                let prefix = self.advance();
+                if !self.peek().is_digit(radix) {
+                    self.skip_alphanumeric();
+                    return self
+                        .error_token(format!("Expect {} digits after '0{}'.", name, prefix));
+                }
+                if let Err(error) = self.digits(radix) {
+                    return error;
+                }
+                if self.peek().is_ascii_alphanumeric() {
+                    let digit = self.peek();
+                    self.skip_alphanumeric();
+                    return self
+                        .error_token(format!("Invalid digit '{}' in {} literal.", digit, name));
+                }
+                return self.make_token(TOKEN_INTEGER);
+            }
         }
 
+        // the first digit is already consumed
+        self.current -= 1;
+        if let Err(error) = self.digits(10) {
+            return error;
+        }
+        let mut token = TOKEN_INTEGER;
+
         // Look for a fractional part.
         if self.peek() == '.' && is_digit(self.peek_next()) {
             // Consume the ".".
             self.advance();
+            if let Err(error) = self.digits(10) {
+                return error;
+            }
+            token = TOKEN_NUMBER;
+        }
+
+        if self.peek() == 'e' || self.peek() == 'E' {
+            self.advance();
+            if self.peek() == '+' || self.peek() == '-' {
+                self.advance();
+            }
+            if !is_digit(self.peek()) {
+                self.skip_alphanumeric();
+                return self.error_token("Expect digits in exponent.".into());
+            }
+            if let Err(error) = self.digits(10) {
+                return error;
+            }
+            token = TOKEN_NUMBER;
+        }
+
+        if is_alpha(self.peek()) {
+            self.skip_alphanumeric();
+            return self.error_token("Invalid character in number literal.".into());
+        }
+        return self.make_token(token);
+    }
 
-            while is_digit(self.peek()) {
+    /// digits in the given radix, where an underscore may separate two digits
+    fn digits(&mut self, radix: u32) -> Result<(), Token> {
+        while self.peek().is_digit(radix) {
+            self.advance();
+            if self.peek() == '_' {
+                if !self.peek_next().is_digit(radix) {
+                    self.advance();
+                    self.skip_alphanumeric();
+                    return Err(self.error_token("Underscore must separate digits.".into()));
+                }
                 self.advance();
             }
         }
+        Ok(())
+    }
 
-        return self.make_token(TOKEN_NUMBER);
+    /// skip the rest of a malformed literal so it is reported only once
+    fn skip_alphanumeric(&mut self) {
+        while is_alpha(self.peek()) || is_digit(self.peek()) {
+            self.advance();
+        }
     }
 
     fn identifier(&mut self) -> Token {
@@ -283,27 +512,61 @@ impl Scanner {
 
     fn identifiertype(&mut self) -> TokenType {
         match self.source[self.start] {
-            'a' => return self.check_keyword(1, 2, "nd", TOKEN_AND),
-            'c' => return self.check_keyword(1, 4, "lass", TOKEN_CLASS),
-            'e' => return self.check_keyword(1, 3, "lse", TOKEN_ELSE),
+            'a' => {
+                if self.current - self.start > 1 {
+                    match self.source[self.start + 1] {
+                        'n' => return self.check_keyword(2, 1, "d", TOKEN_AND),
+                        's' => return self.check_keyword(2, 0, "", TOKEN_AS),
+                        _ => {}
+                    }
+                }
+            }
+            'c' => {
+                if self.current - self.start > 1 {
+                    match self.source[self.start + 1] {
+                        'l' => return self.check_keyword(2, 3, "ass", TOKEN_CLASS),
+                        'o' => return self.check_keyword(2, 3, "nst", TOKEN_CONST),
+                        _ => {}
+                    }
+                }
+            }
+            'e' => {
+                if self.current - self.start > 1 {
+                    match self.source[self.start + 1] {
+                        'l' => return self.check_keyword(2, 2, "se", TOKEN_ELSE),
+                        'x' => return self.check_keyword(2, 4, "port", TOKEN_EXPORT),
+                        _ => {}
+                    }
+                }
+            }
             'f' => {
                 if self.current - self.start > 1 {
                     match self.source[self.start + 1] {
                         'a' => return self.check_keyword(2, 3, "lse", TOKEN_FALSE),
                         'o' => return self.check_keyword(2, 1, "r", TOKEN_FOR),
+                        'r' => return self.check_keyword(2, 2, "om", TOKEN_FROM),
                         'u' => return self.check_keyword(2, 1, "n", TOKEN_FUN),
                         _ => {}
                     }
                 }
             }
-            'i' => return self.check_keyword(1, 1, "f", TOKEN_IF),
+            'i' => {
+                if self.current - self.start > 1 {
+                    match self.source[self.start + 1] {
+                        'f' => return self.check_keyword(2, 0, "", TOKEN_IF),
+                        'm' => return self.check_keyword(2, 4, "port", TOKEN_IMPORT),
+                        _ => {}
+                    }
+                }
+            }
+            'm' => return self.check_keyword(1, 4, "atch", TOKEN_MATCH),
             'n' => return self.check_keyword(1, 2, "il", TOKEN_NIL),
             'o' => return self.check_keyword(1, 1, "r", TOKEN_OR),
             'p' => return self.check_keyword(1, 4, "rint", TOKEN_PRINT),
             'r' => return self.check_keyword(1, 5, "eturn", TOKEN_RETURN),
             's' => return self.check_keyword(1, 4, "uper", TOKEN_SUPER),
             't' => {
-                if (self.current - self.start > 1) {
+                if self.current - self.start > 1 {
                     match self.source[self.start + 1] {
                         'h' => return self.check_keyword(2, 2, "is", TOKEN_THIS),
                         'r' => return self.check_keyword(2, 2, "ue", TOKEN_TRUE),
@@ -336,9 +599,9 @@ impl Scanner {
 }
 
 fn is_digit(c: char) -> bool {
-    c >= '0' && c <= '9'
+    c.is_ascii_digit()
 }
 
 fn is_alpha(c: char) -> bool {
-    (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || c == '_'
+    c.is_ascii_alphabetic() || c == '_'
 }
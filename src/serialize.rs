@@ -0,0 +1,390 @@
+//! the `.loxc` format: a compiled script saved to disk.
+//!
+//! header: `LOXC`, format version (u16), FNV-1a checksum of the payload (u32).
+//! payload: the script chunk. All integers are little endian, strings and lists are
+//! prefixed with their length as a u32.
+//!
+//! chunk: code length, code bytes, line runs as (line, count) pairs, constants.
+//! constant: a tag byte, then the value; functions nest their own chunk.
+
+use crate::chunk::{Chunk, LineRun};
+use crate::object::{Function, Obj};
+use crate::value::Value;
+use std::rc::Rc;
+
+pub const MAGIC: &[u8; 4] = b"LOXC";
+/// bump whenever the bytecode or this layout changes
+pub const VERSION: u16 = 6;
+const HEADER_LEN: usize = 10;
+/// how deep functions may nest in a file, reading them recurses that deep
+const NESTING_MAX: usize = 256;
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_INT: u8 = 4;
+const TAG_STRING: u8 = 5;
+const TAG_FUNCTION: u8 = 6;
+
+impl Chunk {
+    /// the chunk of a top-level script as a `.loxc` file
+    pub fn serialize(&self) -> Result<Vec<u8>, String> {
+        let mut payload = vec![];
+        write_chunk(&mut payload, self)?;
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(VERSION.to_le_bytes());
+        bytes.extend(checksum(&payload).to_le_bytes());
+        bytes.extend(payload);
+        return Ok(bytes);
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Chunk, String> {
+        if bytes.len() < HEADER_LEN || &bytes[0..4] != MAGIC {
+            return Err("Not a .loxc file.".to_string());
+        }
+        let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        if version != VERSION {
+            return Err(format!(
+                "Unsupported .loxc version {}, expected {}. Recompile the script.",
+                version, VERSION
+            ));
+        }
+        let expected = u32::from_le_bytes([bytes[6], bytes[7], bytes[8], bytes[9]]);
+        let payload = &bytes[HEADER_LEN..];
+        if checksum(payload) != expected {
+            return Err("Checksum mismatch, the .loxc file is corrupt.".to_string());
+        }
+        let mut reader = Reader {
+            bytes: payload,
+            offset: 0,
+            depth: 0,
+        };
+        let chunk = reader.chunk()?;
+        if reader.offset != payload.len() {
+            return Err("Trailing bytes after the script.".to_string());
+        }
+        chunk.verify()?;
+        return Ok(chunk);
+    }
+}
+
+/// is `bytes` meant to be a `.loxc` file, whether or not it is a valid one
+pub fn is_compiled(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// 32-bit FNV-1a
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut hash: u32 = 0x811c9dc5;
+    for byte in bytes {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    return hash;
+}
+
+fn write_u32(out: &mut Vec<u8>, value: usize) {
+    out.extend((value as u32).to_le_bytes());
+}
+
+fn write_string(out: &mut Vec<u8>, value: &str) {
+    write_u32(out, value.len());
+    out.extend(value.as_bytes());
+}
+
+fn write_optional_string(out: &mut Vec<u8>, value: &Option<String>) {
+    match value {
+        Some(value) => {
+            out.push(1);
+            write_string(out, value);
+        }
+        None => out.push(0),
+    }
+}
+
+fn write_chunk(out: &mut Vec<u8>, chunk: &Chunk) -> Result<(), String> {
+    write_u32(out, chunk.codes.len());
+    out.extend(&chunk.codes);
+    let runs = chunk.line_runs();
+    write_u32(out, runs.len());
+    for (i, run) in runs.iter().enumerate() {
+        let end = runs
+            .get(i + 1)
+            .map_or(chunk.codes.len(), |next| next.start as usize);
+        write_u32(out, run.line as usize);
+        write_u32(out, end - run.start as usize);
+    }
+    write_u32(out, chunk.constants.values.len());
+    for constant in &chunk.constants.values {
+        write_value(out, constant)?;
+    }
+    return Ok(());
+}
+
+fn write_value(out: &mut Vec<u8>, value: &Value) -> Result<(), String> {
+    if value.is_nil() {
+        out.push(TAG_NIL);
+    } else if value.is_bool() {
+        out.push(if value.as_bool() { TAG_TRUE } else { TAG_FALSE });
+    } else if value.is_int() {
+        out.push(TAG_INT);
+        out.extend(value.as_int().to_le_bytes());
+    } else if value.is_number() {
+        out.push(TAG_NUMBER);
+        out.extend(value.as_number().to_le_bytes());
+    } else {
+        match value.as_obj() {
+            Some(Obj::string(v)) => {
+                out.push(TAG_STRING);
+                write_string(out, v);
+            }
+            Some(Obj::function(function)) => {
+                out.push(TAG_FUNCTION);
+                write_function(out, function)?;
+            }
+            _ => return Err(format!("Can't serialize the constant {}.", value.repr())),
+        }
+    }
+    return Ok(());
+}
+
+fn write_function(out: &mut Vec<u8>, function: &Function) -> Result<(), String> {
+    write_optional_string(out, &function.name);
+    write_optional_string(out, &function.doc);
+    write_u32(out, function.arity);
+    out.push(function.is_variadic as u8);
+    write_u32(out, function.upvalue_count);
+    write_u32(out, function.params.len());
+    for param in &function.params {
+        write_string(out, param);
+    }
+    write_u32(out, function.defaults.len());
+    for default in &function.defaults {
+        write_value(out, default)?;
+    }
+    return write_chunk(out, &function.chunk);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    /// the functions being read around the current position
+    depth: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], String> {
+        if self.bytes.len() - self.offset < len {
+            return Err("Unexpected end of .loxc file.".to_string());
+        }
+        let bytes = &self.bytes[self.offset..self.offset + len];
+        self.offset += len;
+        return Ok(bytes);
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+    }
+
+    fn u64(&mut self) -> Result<[u8; 8], String> {
+        Ok(self.take(8)?.try_into().unwrap())
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.u32()?;
+        let bytes = self.take(len)?.to_vec();
+        String::from_utf8(bytes).map_err(|_| "Invalid UTF-8 in .loxc string.".to_string())
+    }
+
+    fn optional_string(&mut self) -> Result<Option<String>, String> {
+        match self.u8()? {
+            0 => Ok(None),
+            _ => Ok(Some(self.string()?)),
+        }
+    }
+
+    fn chunk(&mut self) -> Result<Chunk, String> {
+        let mut chunk = Chunk::new();
+        let len = self.u32()?;
+        chunk.codes = self.take(len)?.to_vec();
+        let runs = self.u32()?;
+        let mut covered = 0;
+        for _ in 0..runs {
+            let line = self.u32()?;
+            let count = self.u32()?;
+            if count == 0 || covered + count > len {
+                return Err("Line table does not match the code.".to_string());
+            }
+            chunk.lines.push(LineRun {
+                start: covered as u32,
+                line: line as u32,
+            });
+            covered += count;
+        }
+        if covered != len {
+            return Err("Line table does not cover the code.".to_string());
+        }
+        let constants = self.u32()?;
+        for _ in 0..constants {
+            let value = self.value()?;
+            chunk.add_constant(value);
+        }
+        return Ok(chunk);
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        let value = match self.u8()? {
+            TAG_NIL => Value::nil_val(),
+            TAG_FALSE => Value::bool_val(false),
+            TAG_TRUE => Value::bool_val(true),
+            TAG_NUMBER => Value::number_val(f64::from_le_bytes(self.u64()?)),
+            TAG_INT => Value::int_val(i64::from_le_bytes(self.u64()?)),
+            TAG_STRING => Value::string_val(self.string()?),
+            TAG_FUNCTION => Value::obj(Obj::function(Rc::new(self.function()?))),
+            tag => return Err(format!("Unknown constant tag {}.", tag)),
+        };
+        return Ok(value);
+    }
+
+    fn function(&mut self) -> Result<Function, String> {
+        if self.depth == NESTING_MAX {
+            return Err("Functions nested too deeply.".to_string());
+        }
+        self.depth += 1;
+        let mut function = Function::new(self.optional_string()?);
+        function.doc = self.optional_string()?;
+        function.arity = self.u32()?;
+        function.is_variadic = self.u8()? != 0;
+        function.upvalue_count = self.u32()?;
+        for _ in 0..self.u32()? {
+            function.params.push(self.string()?);
+        }
+        for _ in 0..self.u32()? {
+            function.defaults.push(self.value()?);
+        }
+        function.chunk = self.chunk()?;
+        self.depth -= 1;
+        return Ok(function);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a `.loxc` file around `payload`, with a valid header
+    fn file(payload: &[u8]) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(VERSION.to_le_bytes());
+        bytes.extend(checksum(payload).to_le_bytes());
+        bytes.extend(payload);
+        bytes
+    }
+
+    /// a compiled script with functions, strings, integers and floats among its constants
+    fn compiled() -> Vec<u8> {
+        let source = "
+            const NAME = \"lox\";
+            fun scale(x, factor = 2.5) {
+                fun twice(y) { return y * 2; }
+                return twice(x) * factor;
+            }
+            var result = scale(4);
+            print NAME + \"!\";
+        ";
+        let mut vm = crate::vm::VM::new(crate::vm::VMConfig::default());
+        vm.compile(source).unwrap().serialize().unwrap()
+    }
+
+    /// a script with no code whose only constant is a function nested `depth` deep
+    fn nested_functions(depth: usize) -> Vec<u8> {
+        let empty_chunk = [0u8; 12];
+        let mut payload = empty_chunk.to_vec();
+        for _ in 0..depth {
+            let mut function = vec![0, 0];
+            function.extend([0u8; 4]);
+            function.push(0);
+            function.extend([0u8; 12]);
+            function.extend(&payload);
+            payload = vec![0u8; 8];
+            payload.extend(1u32.to_le_bytes());
+            payload.push(TAG_FUNCTION);
+            payload.extend(function);
+        }
+        payload
+    }
+
+    #[test]
+    fn round_trip_keeps_the_bytes() {
+        let bytes = compiled();
+        let chunk = Chunk::deserialize(&bytes).unwrap();
+        assert_eq!(chunk.serialize().unwrap(), bytes);
+    }
+
+    #[test]
+    fn truncated_files_are_rejected() {
+        let bytes = compiled();
+        for len in 0..bytes.len() {
+            let error = Chunk::deserialize(&bytes[..len]).unwrap_err();
+            if len < HEADER_LEN {
+                assert_eq!(error, "Not a .loxc file.");
+            } else {
+                assert_eq!(error, "Checksum mismatch, the .loxc file is corrupt.");
+            }
+        }
+        // with a checksum that matches, the reader itself finds the end
+        let payload = &bytes[HEADER_LEN..];
+        for len in 0..payload.len() {
+            assert!(Chunk::deserialize(&file(&payload[..len])).is_err());
+        }
+        let error = Chunk::deserialize(&file(&payload[..payload.len() - 1])).unwrap_err();
+        assert_eq!(error, "Unexpected end of .loxc file.");
+    }
+
+    #[test]
+    fn corrupt_files_are_rejected() {
+        let mut bytes = compiled();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert_eq!(
+            Chunk::deserialize(&bytes).unwrap_err(),
+            "Checksum mismatch, the .loxc file is corrupt."
+        );
+
+        let mut bytes = compiled();
+        bytes[4..6].copy_from_slice(&(VERSION - 1).to_le_bytes());
+        assert_eq!(
+            Chunk::deserialize(&bytes).unwrap_err(),
+            format!(
+                "Unsupported .loxc version {}, expected {}. Recompile the script.",
+                VERSION - 1,
+                VERSION
+            )
+        );
+
+        let mut payload = compiled()[HEADER_LEN..].to_vec();
+        payload.push(0);
+        assert_eq!(
+            Chunk::deserialize(&file(&payload)).unwrap_err(),
+            "Trailing bytes after the script."
+        );
+    }
+
+    #[test]
+    fn deep_nesting_is_an_error() {
+        let result = Chunk::deserialize(&file(&nested_functions(10_000)));
+        assert_eq!(
+            result.err(),
+            Some("Functions nested too deeply.".to_string())
+        );
+        // within the limit, reading gets as far as the verifier
+        let result = Chunk::deserialize(&file(&nested_functions(NESTING_MAX)));
+        assert!(result.unwrap_err().contains("Bad bytecode"));
+    }
+}
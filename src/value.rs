@@ -1,22 +1,37 @@
 use crate::object::Obj;
-use crate::value::Value::{boolean, nil, number, obj};
-use std::cmp::PartialEq;
-use std::fmt::{Debug, Formatter};
+#[cfg(not(feature = "nan_boxing"))]
+use crate::value::Value::{boolean, int, nil, number, obj};
+use crate::vm::LoxError;
+use std::cell::RefCell;
+use std::fmt;
+use std::fmt::{Debug, Display};
+use std::rc::Rc;
 
 /// The constant pool is an array of values.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct ValueArray {
     pub values: Vec<Value>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[cfg(feature = "nan_boxing")]
+mod nan_boxing;
+#[cfg(feature = "nan_boxing")]
+pub use nan_boxing::Value;
+
+/// a value as a Rust enum, unless the `nan_boxing` feature packs it into a word.
+/// Code outside this module goes through the constructors and predicates, which both share
+#[cfg(not(feature = "nan_boxing"))]
+#[derive(Debug, Clone, PartialEq, Default)]
 pub enum Value {
     boolean(bool),
     number(f64),
+    int(i64),
     obj(Obj),
+    #[default]
     nil,
 }
 
+#[cfg(not(feature = "nan_boxing"))]
 impl Value {
     pub fn nil_val() -> Self {
         nil
@@ -24,14 +39,13 @@ impl Value {
     pub fn number_val(value: f64) -> Self {
         number(value)
     }
+    pub fn int_val(value: i64) -> Self {
+        int(value)
+    }
     pub fn bool_val(value: bool) -> Self {
         boolean(value)
     }
 
-    pub fn string_val(str: String) -> Self {
-        obj(Obj::string(str))
-    }
-
     pub fn as_bool(&self) -> bool {
         match self {
             boolean(v) => *v,
@@ -39,52 +53,171 @@ impl Value {
         }
     }
 
+    /// the value of an int or a float as a float
     pub fn as_number(&self) -> f64 {
         match self {
             number(v) => *v,
+            int(v) => *v as f64,
             _ => 0f64,
         }
     }
 
-    pub fn as_string(&self) -> &str {
+    pub fn as_int(&self) -> i64 {
         match self {
-            obj(v) => v.string_val(),
-            _ => "",
+            int(v) => *v,
+            _ => 0,
         }
     }
 
-    pub fn is_bool(&self) -> bool {
+    /// the object the value holds, if it is one
+    pub fn as_obj(&self) -> Option<&Obj> {
         match self {
-            boolean(_) => true,
-            _ => false,
+            obj(v) => Some(v),
+            _ => None,
         }
     }
 
+    pub fn is_bool(&self) -> bool {
+        matches!(self, boolean(_))
+    }
+
+    /// an int or a float
     pub fn is_number(&self) -> bool {
-        match self {
-            number(_) => true,
-            _ => false,
+        matches!(self, number(_) | int(_))
+    }
+
+    pub fn is_int(&self) -> bool {
+        matches!(self, int(_))
+    }
+
+    pub fn is_nil(&self) -> bool {
+        matches!(self, nil)
+    }
+}
+
+/// the parts that work the same whatever the representation
+impl Value {
+    pub fn string_val(str: String) -> Self {
+        Value::obj(Obj::string(str))
+    }
+
+    pub fn list_val(items: Vec<Value>) -> Self {
+        Value::obj(Obj::list(Rc::new(RefCell::new(items))))
+    }
+
+    pub fn as_string(&self) -> &str {
+        match self.as_obj() {
+            Some(v) => v.string_val(),
+            None => "",
         }
     }
 
     pub fn is_string(&self) -> bool {
-        match self {
-            obj(o) => o.is_string(),
-            _ => false,
+        self.as_obj().is_some_and(Obj::is_string)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Value::number_val(value)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Value::int_val(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::bool_val(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::string_val(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::string_val(value)
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(items: Vec<Value>) -> Self {
+        Value::list_val(items)
+    }
+}
+
+/// `None` is nil
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Value::nil_val(), Into::into)
+    }
+}
+
+fn conversion_error(expected: &str, value: &Value) -> LoxError {
+    LoxError::conversion(format!("Expected {} but got {}.", expected, value.repr()))
+}
+
+/// ints widen to floats
+impl TryFrom<Value> for f64 {
+    type Error = LoxError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        if !value.is_number() {
+            return Err(conversion_error("a number", &value));
         }
+        return Ok(value.as_number());
     }
+}
 
-    pub fn is_nil(&self) -> bool {
-        match self {
-            nil => true,
-            _ => false,
+impl TryFrom<Value> for i64 {
+    type Error = LoxError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        if !value.is_int() {
+            return Err(conversion_error("an integer", &value));
         }
+        return Ok(value.as_int());
     }
 }
 
-impl Default for Value {
-    fn default() -> Self {
-        nil
+impl TryFrom<Value> for bool {
+    type Error = LoxError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        if !value.is_bool() {
+            return Err(conversion_error("a boolean", &value));
+        }
+        return Ok(value.as_bool());
+    }
+}
+
+impl TryFrom<Value> for String {
+    type Error = LoxError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value.as_obj() {
+            Some(Obj::string(v)) => Ok(v.clone()),
+            _ => Err(conversion_error("a string", &value)),
+        }
+    }
+}
+
+/// the list is copied, later changes to either side are not shared
+impl TryFrom<Value> for Vec<Value> {
+    type Error = LoxError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value.as_obj() {
+            Some(Obj::list(items)) => Ok(items.borrow().clone()),
+            _ => Err(conversion_error("a list", &value)),
+        }
     }
 }
 
@@ -102,20 +235,76 @@ impl ValueArray {
     }
 }
 
-pub fn print_value(value: Value) {
-    match value {
-        nil => print!("nil"),
-        obj(o) => o.print_obj(),
-        _ => print!("{:?}", value),
+/// how a value looks in the REPL and the disassembler: strings quoted, floats keep their `.0`
+pub struct Repr<'a>(&'a Value);
+
+impl Value {
+    pub fn repr(&self) -> Repr<'_> {
+        return Repr(self);
+    }
+
+    /// `seen` holds the lists being written, so a list containing itself prints as `[...]`
+    pub(crate) fn write(
+        &self,
+        f: &mut fmt::Formatter,
+        repr: bool,
+        seen: &mut Vec<*const RefCell<Vec<Value>>>,
+    ) -> fmt::Result {
+        if let Some(o) = self.as_obj() {
+            return o.write(f, repr, seen);
+        }
+        if self.is_bool() {
+            return write!(f, "{}", self.as_bool());
+        }
+        if self.is_int() {
+            return write!(f, "{}", self.as_int());
+        }
+        if self.is_number() {
+            return write_number(f, self.as_number(), repr);
+        }
+        return write!(f, "nil");
+    }
+}
+
+fn write_number(f: &mut fmt::Formatter, v: f64, repr: bool) -> fmt::Result {
+    if v.is_nan() {
+        return write!(f, "nan");
+    }
+    let abs = v.abs();
+    if abs.is_finite() && (abs >= 1e21 || (abs != 0.0 && abs < 1e-6)) {
+        return write!(f, "{:e}", v);
+    }
+    if repr {
+        return write!(f, "{:?}", v);
+    }
+    return write!(f, "{}", v);
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, false, &mut vec![])
     }
 }
 
-pub fn values_equal(a: Value, b: Value) -> bool {
-    match (a, b) {
-        (number(a), number(b)) => a == b,
-        (boolean(a), boolean(b)) => a == b,
-        (obj(a), obj(b)) => a == b,
-        (nil, nil) => true,
-        (_, _) => false,
+impl Display for Repr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.write(f, true, &mut vec![])
+    }
+}
+
+/// ints and floats compare by numeric value
+pub fn values_equal(a: &Value, b: &Value) -> bool {
+    if a.is_int() && b.is_int() {
+        return a.as_int() == b.as_int();
+    }
+    if a.is_number() && b.is_number() {
+        return a.as_number() == b.as_number();
+    }
+    if a.is_bool() && b.is_bool() {
+        return a.as_bool() == b.as_bool();
+    }
+    match (a.as_obj(), b.as_obj()) {
+        (Some(a), Some(b)) => a == b,
+        _ => a.is_nil() && b.is_nil(),
     }
 }
@@ -0,0 +1,192 @@
+//! `Value` packed into a single 64-bit word, selected by the `nan_boxing` feature.
+//!
+//! floats are stored as they are, with every NaN made the canonical one. Everything else
+//! lives in a quiet NaN with bit 50 set, which no float uses: the sign bit and bits 48-49
+//! tag the kind of value and the low 48 bits hold it. Ints that don't fit in 48 bits and
+//! objects are reference counted, the word holds the pointer.
+
+use crate::object::Obj;
+use std::fmt;
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+/// exponent, quiet bit and bit 50
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+const SIGN: u64 = 1 << 63;
+const PAYLOAD: u64 = (1 << 48) - 1;
+const TAG: u64 = SIGN | QNAN | 3 << 48;
+
+const TAG_SINGLETON: u64 = QNAN;
+/// an int in the 48-bit payload
+const TAG_INT: u64 = QNAN | 1 << 48;
+/// a pointer to an `Rc<i64>`
+const TAG_BIG_INT: u64 = QNAN | 2 << 48;
+/// a pointer to an `Rc<Obj>`
+const TAG_OBJ: u64 = SIGN | QNAN;
+
+const NIL: u64 = TAG_SINGLETON | 1;
+const FALSE: u64 = TAG_SINGLETON | 2;
+const TRUE: u64 = TAG_SINGLETON | 3;
+
+const INT_MIN: i64 = -(1 << 47);
+const INT_MAX: i64 = (1 << 47) - 1;
+
+/// the marker keeps values to one thread, like the `Rc`s they may point to
+pub struct Value(u64, PhantomData<Rc<Obj>>);
+
+impl Value {
+    fn from_bits(bits: u64) -> Self {
+        Value(bits, PhantomData)
+    }
+
+    pub fn nil_val() -> Self {
+        Value::from_bits(NIL)
+    }
+
+    pub fn number_val(value: f64) -> Self {
+        if value.is_nan() {
+            return Value::from_bits(f64::NAN.to_bits());
+        }
+        Value::from_bits(value.to_bits())
+    }
+
+    pub fn int_val(value: i64) -> Self {
+        if (INT_MIN..=INT_MAX).contains(&value) {
+            return Value::from_bits(TAG_INT | (value as u64 & PAYLOAD));
+        }
+        Value::from_bits(TAG_BIG_INT | pointer(Rc::into_raw(Rc::new(value))))
+    }
+
+    pub fn bool_val(value: bool) -> Self {
+        Value::from_bits(if value { TRUE } else { FALSE })
+    }
+
+    pub fn obj(value: Obj) -> Self {
+        Value::from_bits(TAG_OBJ | pointer(Rc::into_raw(Rc::new(value))))
+    }
+
+    /// one of the `TAG_` constants, anything else for a float
+    fn tag(&self) -> u64 {
+        self.0 & TAG
+    }
+
+    fn is_float(&self) -> bool {
+        self.0 & QNAN != QNAN
+    }
+
+    pub fn as_bool(&self) -> bool {
+        self.0 == TRUE
+    }
+
+    /// the value of an int or a float as a float
+    pub fn as_number(&self) -> f64 {
+        if self.is_float() {
+            return f64::from_bits(self.0);
+        }
+        if self.is_int() {
+            return self.as_int() as f64;
+        }
+        0f64
+    }
+
+    pub fn as_int(&self) -> i64 {
+        match self.tag() {
+            // shift the payload's sign bit into place and back to extend it
+            TAG_INT => ((self.0 << 16) as i64) >> 16,
+            // SAFETY: the word holds a pointer from `Rc::into_raw` that this value owns
+            TAG_BIG_INT => unsafe { *((self.0 & PAYLOAD) as *const i64) },
+            _ => 0,
+        }
+    }
+
+    pub fn as_obj(&self) -> Option<&Obj> {
+        if self.tag() != TAG_OBJ {
+            return None;
+        }
+        // SAFETY: the word holds a pointer from `Rc::into_raw` that this value owns, so the
+        // object lives at least as long as the borrow of `self`
+        Some(unsafe { &*((self.0 & PAYLOAD) as *const Obj) })
+    }
+
+    pub fn is_bool(&self) -> bool {
+        self.0 == TRUE || self.0 == FALSE
+    }
+
+    /// an int or a float
+    pub fn is_number(&self) -> bool {
+        self.is_float() || self.is_int()
+    }
+
+    pub fn is_int(&self) -> bool {
+        self.tag() == TAG_INT || self.tag() == TAG_BIG_INT
+    }
+
+    pub fn is_nil(&self) -> bool {
+        self.0 == NIL
+    }
+}
+
+/// the address of a heap value as a payload, user space addresses fit in 48 bits
+fn pointer<T>(ptr: *const T) -> u64 {
+    let address = ptr as u64;
+    assert!(
+        address <= PAYLOAD,
+        "pointer does not fit in a NaN-boxed value"
+    );
+    address
+}
+
+impl Clone for Value {
+    fn clone(&self) -> Self {
+        let ptr = self.0 & PAYLOAD;
+        // SAFETY: the pointers come from `Rc::into_raw` and are alive while `self` is
+        match self.tag() {
+            TAG_OBJ => unsafe { Rc::increment_strong_count(ptr as *const Obj) },
+            TAG_BIG_INT => unsafe { Rc::increment_strong_count(ptr as *const i64) },
+            _ => {}
+        }
+        Value::from_bits(self.0)
+    }
+}
+
+impl Drop for Value {
+    fn drop(&mut self) {
+        let ptr = self.0 & PAYLOAD;
+        // SAFETY: each value owns one strong count of the `Rc` it points to
+        match self.tag() {
+            TAG_OBJ => unsafe { Rc::decrement_strong_count(ptr as *const Obj) },
+            TAG_BIG_INT => unsafe { Rc::decrement_strong_count(ptr as *const i64) },
+            _ => {}
+        }
+    }
+}
+
+impl Default for Value {
+    fn default() -> Self {
+        Value::nil_val()
+    }
+}
+
+/// like the enum's derived `PartialEq`: an int never equals a float
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        if self.is_int() && other.is_int() {
+            return self.as_int() == other.as_int();
+        }
+        if self.is_float() && other.is_float() {
+            return self.as_number() == other.as_number();
+        }
+        match (self.as_obj(), other.as_obj()) {
+            (Some(a), Some(b)) => a == b,
+            // nil and the booleans are single words, ints and floats never share one
+            _ => self.0 == other.0,
+        }
+    }
+}
+
+impl Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.repr())
+    }
+}
@@ -0,0 +1,627 @@
+//! checks a chunk the compiler did not just produce before the VM runs it, so a malformed
+//! one is rejected with an error instead of panicking or reading the wrong slots.
+
+use crate::chunk::{read_long, Chunk, OpCode};
+use crate::object::{Function, Obj};
+use crate::value::Value;
+use std::collections::HashSet;
+
+/// what the verifier knows about the function a chunk belongs to
+struct Code<'a> {
+    chunk: &'a Chunk,
+    name: String,
+    /// slot zero and the parameters, already on the stack when the code starts
+    params: usize,
+    upvalue_count: usize,
+}
+
+/// a decoded instruction
+struct Instruction {
+    op: OpCode,
+    /// offset of the next instruction
+    next: usize,
+    /// values the instruction pops before pushing `pushes`
+    pops: usize,
+    pushes: usize,
+    /// where control can go instead of, or besides, `next`
+    targets: Vec<usize>,
+    /// control never reaches `next`
+    terminates: bool,
+}
+
+impl Chunk {
+    /// verify the chunk as the code of a top-level script, and every function nested in its
+    /// constant pool
+    pub fn verify(&self) -> Result<(), String> {
+        let script = Code {
+            chunk: self,
+            name: "<script>".to_string(),
+            params: 1,
+            upvalue_count: 0,
+        };
+        script.verify()?;
+        // each function once, however many closures are made from it
+        let mut visited: HashSet<*const Function> = HashSet::new();
+        let mut pending: Vec<&Function> = self.functions().collect();
+        while let Some(function) = pending.pop() {
+            if visited.insert(function) {
+                verify_function(function)?;
+                pending.extend(function.chunk.functions());
+            }
+        }
+        return Ok(());
+    }
+}
+
+fn verify_function(function: &Function) -> Result<(), String> {
+    let name = function.to_string();
+    // the compiler names every function, lambdas included, only the top-level script has none
+    if function.name.is_none() {
+        return Err(format!(
+            "Bad bytecode in {}: a nested function has no name.",
+            name
+        ));
+    }
+    if function.params.len() != function.arity {
+        return Err(format!(
+            "Bad bytecode in {}: it has {} parameter names for {} parameters.",
+            name,
+            function.params.len(),
+            function.arity
+        ));
+    }
+    if function.defaults.len() > function.arity {
+        return Err(format!(
+            "Bad bytecode in {}: it has more defaults than parameters.",
+            name
+        ));
+    }
+    let code = Code {
+        chunk: &function.chunk,
+        name,
+        params: 1 + function.arity + function.is_variadic as usize,
+        upvalue_count: function.upvalue_count,
+    };
+    return code.verify();
+}
+
+impl Code<'_> {
+    fn error(&self, offset: usize, message: &str) -> String {
+        format!(
+            "Bad bytecode in {} at offset {}: {}.",
+            self.name, offset, message
+        )
+    }
+
+    fn byte(&self, offset: usize, at: usize) -> Result<u8, String> {
+        match self.chunk.codes.get(at) {
+            Some(byte) => Ok(*byte),
+            None => Err(self.error(offset, "the instruction is cut off")),
+        }
+    }
+
+    fn short(&self, offset: usize, at: usize) -> Result<usize, String> {
+        Ok(((self.byte(offset, at)? as usize) << 8) | self.byte(offset, at + 1)? as usize)
+    }
+
+    /// the constant at the index operand at `at`, 24 bits wide when `long`
+    fn constant(&self, offset: usize, at: usize, long: bool) -> Result<&Value, String> {
+        let index = if long {
+            (self.short(offset, at)? << 8) | self.byte(offset, at + 2)? as usize
+        } else {
+            self.byte(offset, at)? as usize
+        };
+        match self.chunk.constants.values.get(index) {
+            Some(value) => Ok(value),
+            None => Err(self.error(offset, &format!("constant {} is out of range", index))),
+        }
+    }
+
+    /// a constant naming a global, property, keyword or module
+    fn name(&self, offset: usize, at: usize, long: bool) -> Result<(), String> {
+        if !self.constant(offset, at, long)?.is_string() {
+            return Err(self.error(offset, "the name operand is not a string"));
+        }
+        return Ok(());
+    }
+
+    /// a keyword count and that many names, 24-bit when `long`
+    fn keywords(&self, offset: usize, at: usize, long: bool) -> Result<usize, String> {
+        let count = self.byte(offset, at)? as usize;
+        let width = if long { 3 } else { 1 };
+        for i in 0..count {
+            self.name(offset, at + 1 + width * i, long)?;
+        }
+        return Ok(at + 1 + width * count);
+    }
+
+    fn upvalue(&self, offset: usize, index: usize) -> Result<(), String> {
+        if index >= self.upvalue_count {
+            return Err(self.error(offset, &format!("upvalue {} is out of range", index)));
+        }
+        return Ok(());
+    }
+
+    fn decode(&self, offset: usize) -> Result<Instruction, String> {
+        let byte = self.chunk.codes[offset];
+        let Ok(op) = OpCode::try_from(byte) else {
+            return Err(self.error(offset, &format!("unknown opcode {}", byte)));
+        };
+        let simple = |pops, pushes| Instruction {
+            op,
+            next: offset + 1,
+            pops,
+            pushes,
+            targets: vec![],
+            terminates: false,
+        };
+        let with_operand = |pops, pushes| Instruction {
+            next: offset + 2,
+            ..simple(pops, pushes)
+        };
+        let long = op.is_long();
+        // the offset after a constant or slot index operand
+        let after_index = if long { offset + 4 } else { offset + 2 };
+        let with_index = |pops, pushes| Instruction {
+            next: after_index,
+            ..simple(pops, pushes)
+        };
+        let instruction = match op {
+            OpCode::OP_NIL | OpCode::OP_TRUE | OpCode::OP_FALSE => simple(0, 1),
+            OpCode::OP_POP | OpCode::OP_PRINT | OpCode::OP_ECHO | OpCode::OP_CLOSE_UPVALUE => {
+                simple(1, 0)
+            }
+            OpCode::OP_DUP => simple(1, 2),
+            OpCode::OP_NOT | OpCode::OP_NEGATE | OpCode::OP_BIT_NOT => simple(1, 1),
+            OpCode::OP_EQUAL
+            | OpCode::OP_NOT_EQUAL
+            | OpCode::OP_GREATER
+            | OpCode::OP_GREATER_EQUAL
+            | OpCode::OP_LESS
+            | OpCode::OP_LESS_EQUAL
+            | OpCode::OP_ADD
+            | OpCode::OP_SUBTRACT
+            | OpCode::OP_MULTIPLY
+            | OpCode::OP_DIVIDE
+            | OpCode::OP_MODULO
+            | OpCode::OP_BIT_AND
+            | OpCode::OP_BIT_OR
+            | OpCode::OP_BIT_XOR
+            | OpCode::OP_SHIFT_LEFT
+            | OpCode::OP_SHIFT_RIGHT
+            | OpCode::OP_GET_INDEX => simple(2, 1),
+            OpCode::OP_RETURN => Instruction {
+                terminates: true,
+                ..simple(1, 0)
+            },
+            OpCode::OP_CONSTANT | OpCode::OP_CONSTANT_LONG => {
+                self.constant(offset, offset + 1, long)?;
+                with_index(0, 1)
+            }
+            OpCode::OP_GET_GLOBAL
+            | OpCode::OP_GET_GLOBAL_LONG
+            | OpCode::OP_IMPORT
+            | OpCode::OP_IMPORT_LONG => {
+                self.name(offset, offset + 1, long)?;
+                with_index(0, 1)
+            }
+            OpCode::OP_DEFINE_GLOBAL | OpCode::OP_DEFINE_GLOBAL_LONG => {
+                self.name(offset, offset + 1, long)?;
+                with_index(1, 0)
+            }
+            OpCode::OP_SET_GLOBAL
+            | OpCode::OP_SET_GLOBAL_LONG
+            | OpCode::OP_GET_PROPERTY
+            | OpCode::OP_GET_PROPERTY_LONG => {
+                self.name(offset, offset + 1, long)?;
+                with_index(1, 1)
+            }
+            // local slots are checked against the stack depth once it is known
+            OpCode::OP_GET_LOCAL | OpCode::OP_GET_LOCAL_LONG => {
+                self.byte(offset, after_index - 1)?;
+                with_index(0, 1)
+            }
+            OpCode::OP_SET_LOCAL | OpCode::OP_SET_LOCAL_LONG => {
+                self.byte(offset, after_index - 1)?;
+                with_index(1, 1)
+            }
+            OpCode::OP_ADD_LOCAL_CONST => {
+                self.byte(offset, offset + 1)?;
+                self.constant(offset, offset + 2, false)?;
+                Instruction {
+                    next: offset + 3,
+                    ..simple(0, 1)
+                }
+            }
+            OpCode::OP_LESS_LOCALS_JUMP => {
+                let target = offset + 5 + self.short(offset, offset + 3)?;
+                Instruction {
+                    next: offset + 5,
+                    targets: vec![target],
+                    ..simple(0, 0)
+                }
+            }
+            OpCode::OP_INC_LOCAL => {
+                self.byte(offset, offset + 1)?;
+                with_operand(0, 0)
+            }
+            OpCode::OP_MATCH_LIST => {
+                self.byte(offset, offset + 1)?;
+                with_operand(1, 1)
+            }
+            OpCode::OP_GET_UPVALUE => {
+                self.upvalue(offset, self.byte(offset, offset + 1)? as usize)?;
+                with_operand(0, 1)
+            }
+            OpCode::OP_SET_UPVALUE => {
+                self.upvalue(offset, self.byte(offset, offset + 1)? as usize)?;
+                with_operand(1, 1)
+            }
+            OpCode::OP_JUMP | OpCode::OP_JUMP_IF_FALSE | OpCode::OP_JUMP_IF_NOT_NIL => {
+                let target = offset + 3 + self.short(offset, offset + 1)?;
+                let pops = (op != OpCode::OP_JUMP) as usize;
+                Instruction {
+                    next: offset + 3,
+                    targets: vec![target],
+                    terminates: op == OpCode::OP_JUMP,
+                    ..simple(pops, pops)
+                }
+            }
+            OpCode::OP_LOOP => {
+                let jump = self.short(offset, offset + 1)?;
+                let Some(target) = (offset + 3).checked_sub(jump) else {
+                    return Err(self.error(offset, "the loop jumps before the start of the chunk"));
+                };
+                Instruction {
+                    next: offset + 3,
+                    targets: vec![target],
+                    terminates: true,
+                    ..simple(0, 0)
+                }
+            }
+            OpCode::OP_JUMP_TABLE => {
+                let count = self.byte(offset, offset + 3)? as usize;
+                let table = offset + 4;
+                let table_end = table + 2 * (count + 1);
+                let mut targets = vec![];
+                for entry in 0..=count {
+                    let back = self.short(offset, table + 2 * entry)?;
+                    let Some(target) = table_end.checked_sub(back) else {
+                        return Err(self.error(offset, "a jump table entry is out of range"));
+                    };
+                    targets.push(target);
+                }
+                Instruction {
+                    next: table_end,
+                    targets,
+                    terminates: true,
+                    ..simple(1, 0)
+                }
+            }
+            OpCode::OP_CALL => {
+                let arg_count = self.byte(offset, offset + 1)? as usize;
+                with_operand(arg_count + 1, 1)
+            }
+            OpCode::OP_CALL_KW | OpCode::OP_CALL_KW_LONG => {
+                let arg_count = self.byte(offset, offset + 1)? as usize;
+                Instruction {
+                    next: self.keywords(offset, offset + 2, long)?,
+                    ..simple(arg_count + 1, 1)
+                }
+            }
+            OpCode::OP_INVOKE
+            | OpCode::OP_INVOKE_LONG
+            | OpCode::OP_INVOKE_KW
+            | OpCode::OP_INVOKE_KW_LONG => {
+                self.name(offset, offset + 1, long)?;
+                let arg_count = self.byte(offset, after_index)? as usize;
+                let next = match op {
+                    OpCode::OP_INVOKE_KW | OpCode::OP_INVOKE_KW_LONG => {
+                        self.keywords(offset, after_index + 1, long)?
+                    }
+                    _ => after_index + 1,
+                };
+                Instruction {
+                    next,
+                    ..simple(arg_count + 1, 1)
+                }
+            }
+            OpCode::OP_CLOSURE | OpCode::OP_CLOSURE_LONG => {
+                let Some(Obj::function(function)) =
+                    self.constant(offset, offset + 1, long)?.as_obj()
+                else {
+                    return Err(self.error(offset, "the closure operand is not a function"));
+                };
+                // the captured locals are checked against the stack depth once it is known
+                for i in 0..function.upvalue_count {
+                    let is_local = self.byte(offset, after_index + 3 * i)?;
+                    let index = self.short(offset, after_index + 1 + 3 * i)?;
+                    if is_local > 1 {
+                        return Err(self.error(offset, "a capture is neither local nor an upvalue"));
+                    }
+                    if is_local == 0 {
+                        self.upvalue(offset, index)?;
+                    }
+                }
+                Instruction {
+                    next: after_index + 3 * function.upvalue_count,
+                    ..simple(0, 1)
+                }
+            }
+        };
+        return Ok(instruction);
+    }
+
+    /// the local slots the instruction at `offset` reads, writes or captures
+    fn slots(&self, instruction: &Instruction, offset: usize) -> Vec<usize> {
+        let codes = &self.chunk.codes;
+        match instruction.op {
+            OpCode::OP_GET_LOCAL
+            | OpCode::OP_SET_LOCAL
+            | OpCode::OP_ADD_LOCAL_CONST
+            | OpCode::OP_INC_LOCAL => vec![codes[offset + 1] as usize],
+            OpCode::OP_LESS_LOCALS_JUMP => {
+                vec![codes[offset + 1] as usize, codes[offset + 2] as usize]
+            }
+            OpCode::OP_GET_LOCAL_LONG | OpCode::OP_SET_LOCAL_LONG => {
+                vec![read_long(codes, offset + 1)]
+            }
+            OpCode::OP_CLOSURE | OpCode::OP_CLOSURE_LONG => {
+                let captures = if instruction.op.is_long() {
+                    offset + 4
+                } else {
+                    offset + 2
+                };
+                (captures..instruction.next)
+                    .step_by(3)
+                    .filter(|at| codes[*at] == 1)
+                    .map(|at| (codes[at + 1] as usize) << 8 | codes[at + 2] as usize)
+                    .collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    fn verify(&self) -> Result<(), String> {
+        let len = self.chunk.codes.len();
+        // the runs start at offset zero and each one starts inside the code, after the last
+        let runs = self.chunk.line_runs();
+        let ordered = runs.windows(2).all(|pair| pair[0].start < pair[1].start);
+        let covered = runs.first().is_some_and(|run| run.start == 0)
+            && runs.last().is_some_and(|run| (run.start as usize) < len);
+        if len > 0 && !(ordered && covered) {
+            return Err(format!(
+                "Bad bytecode in {}: the line table does not match the code.",
+                self.name
+            ));
+        }
+        // decode everything first, so jumps can be checked against instruction boundaries
+        let mut decoded: Vec<Option<Instruction>> = (0..len).map(|_| None).collect();
+        let mut offset = 0;
+        while offset < len {
+            let instruction = self.decode(offset)?;
+            let next = instruction.next;
+            decoded[offset] = Some(instruction);
+            offset = next;
+        }
+
+        // every path has to reach an instruction with the same number of values on the stack
+        let mut depths: Vec<Option<usize>> = vec![None; len];
+        // (the instruction control comes from, where it goes, the stack depth there)
+        let mut work = vec![(0, 0, self.params)];
+        while let Some((from, offset, depth)) = work.pop() {
+            let Some(instruction) = decoded.get(offset).and_then(Option::as_ref) else {
+                let message = if offset >= len {
+                    "execution runs off the end of the chunk".to_string()
+                } else {
+                    format!("jump target {} is not the start of an instruction", offset)
+                };
+                return Err(self.error(from, &message));
+            };
+            match depths[offset] {
+                Some(known) if known == depth => continue,
+                Some(known) => {
+                    let message = format!(
+                        "the stack holds {} values on one path and {} on another",
+                        known, depth
+                    );
+                    return Err(self.error(offset, &message));
+                }
+                None => depths[offset] = Some(depth),
+            }
+            // slot zero belongs to the frame and is never popped
+            if depth < instruction.pops + 1 {
+                return Err(self.error(offset, "the stack underflows"));
+            }
+            // a closure can capture the slot it is about to be pushed into, which is how a
+            // local function refers to itself
+            let on_stack = match instruction.op {
+                OpCode::OP_CLOSURE | OpCode::OP_CLOSURE_LONG => depth + 1,
+                _ => depth,
+            };
+            for slot in self.slots(instruction, offset) {
+                if slot >= on_stack {
+                    return Err(
+                        self.error(offset, &format!("local slot {} is not on the stack", slot))
+                    );
+                }
+            }
+            let after = depth - instruction.pops + instruction.pushes;
+            if !instruction.terminates {
+                work.push((offset, instruction.next, after));
+            }
+            for target in &instruction.targets {
+                work.push((offset, *target, after));
+            }
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chunk::{Chunk, OpCode};
+    use crate::object::{Function, Obj};
+    use crate::value::Value;
+    use crate::vm::InterpretResult::INTERPRET_OK;
+    use crate::vm::{VMConfig, VM};
+    use std::path::Path;
+    use std::rc::Rc;
+
+    fn quiet_vm(opt_level: u8) -> VM {
+        VM::new(VMConfig {
+            out: Box::new(std::io::sink()),
+            err: Box::new(std::io::sink()),
+            opt_level,
+            ..VMConfig::default()
+        })
+    }
+
+    /// compile `source`, save it as a `.loxc` file, load it back and run it, returning the
+    /// global `result`
+    fn run_compiled(source: &str, opt_level: u8) -> Value {
+        let bytes = quiet_vm(opt_level)
+            .compile(source)
+            .unwrap()
+            .serialize()
+            .unwrap();
+        let chunk = Chunk::deserialize(&bytes).unwrap();
+        let mut vm = quiet_vm(opt_level);
+        assert_eq!(
+            vm.interpret_chunk(chunk, Path::new("test.loxc")),
+            INTERPRET_OK
+        );
+        vm.get_global("result").unwrap()
+    }
+
+    /// a chunk holding `codes`, all on line 1, with a single number constant
+    fn chunk_of(codes: &[u8]) -> Chunk {
+        let mut chunk = Chunk::new();
+        chunk.add_constant(Value::int_val(1));
+        for byte in codes {
+            chunk.write_chunk(*byte, 1);
+        }
+        chunk
+    }
+
+    /// the verifier error for `codes`
+    fn rejection(codes: &[u8]) -> String {
+        chunk_of(codes).verify().unwrap_err()
+    }
+
+    /// code that makes `count` closures of the function constant `inner` and drops them
+    fn closures_of(inner: Option<Function>, count: usize) -> Chunk {
+        let mut chunk = Chunk::new();
+        if let Some(inner) = inner {
+            chunk.add_constant(Value::obj(Obj::function(Rc::new(inner))));
+            for _ in 0..count {
+                chunk.write_chunk(OpCode::OP_CLOSURE, 1);
+                chunk.write_chunk(0, 1);
+                chunk.write_chunk(OpCode::OP_POP, 1);
+            }
+        }
+        chunk.write_chunk(OpCode::OP_NIL, 1);
+        chunk.write_chunk(OpCode::OP_RETURN, 1);
+        chunk
+    }
+
+    #[test]
+    fn compiled_programs_verify() {
+        let source = "
+            fun adder(n) { fun add(x) { return x + n; } return add; }
+            fun sum(i, total) {
+                if (i == 10) return total;
+                if (i % 2 == 0) return sum(i + 1, total + adder(i)(1));
+                return sum(i + 1, total - 1);
+            }
+            const total = sum(0, 0);
+            match (total) { 1 | 3 => print \"odd\"; [first, _] => print first; _ => print total; }
+            print total > 5 ? \"big\" : \"small\";
+        ";
+        for opt_level in 0..=2 {
+            let chunk = quiet_vm(opt_level).compile(source).unwrap();
+            assert_eq!(chunk.verify(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn hand_written_code_verifies() {
+        let nil = OpCode::OP_NIL as u8;
+        let ret = OpCode::OP_RETURN as u8;
+        assert_eq!(chunk_of(&[nil, ret]).verify(), Ok(()));
+        let constant = OpCode::OP_CONSTANT as u8;
+        let pop = OpCode::OP_POP as u8;
+        assert_eq!(chunk_of(&[constant, 0, pop, nil, ret]).verify(), Ok(()));
+    }
+
+    #[test]
+    fn bad_code_is_rejected() {
+        let nil = OpCode::OP_NIL as u8;
+        let ret = OpCode::OP_RETURN as u8;
+        let pop = OpCode::OP_POP as u8;
+        let constant = OpCode::OP_CONSTANT as u8;
+        let jump = OpCode::OP_JUMP as u8;
+        let looping = OpCode::OP_LOOP as u8;
+        let cases: [(&[u8], &str); 8] = [
+            (&[250, nil, ret], "unknown opcode 250"),
+            (&[constant, 3, pop, nil, ret], "constant 3 is out of range"),
+            (&[nil, ret, constant], "the instruction is cut off"),
+            (&[pop, nil, ret], "the stack underflows"),
+            (&[nil], "execution runs off the end of the chunk"),
+            (
+                &[jump, 0, 1, constant, 0, pop, nil, ret],
+                "jump target 4 is not the start of an instruction",
+            ),
+            (
+                &[looping, 0, 10, nil, ret],
+                "the loop jumps before the start",
+            ),
+            (
+                &[nil, looping, 0, 4],
+                "the stack holds 1 values on one path and 2 on another",
+            ),
+        ];
+        for (codes, message) in cases {
+            let error = rejection(codes);
+            assert!(error.contains(message), "{:?} gave {}", codes, error);
+        }
+    }
+
+    #[test]
+    fn unnamed_nested_functions_are_rejected() {
+        let mut function = Function::new(None);
+        function.chunk = closures_of(None, 0);
+        let error = closures_of(Some(function), 1).verify().unwrap_err();
+        assert!(error.contains("a nested function has no name"), "{}", error);
+    }
+
+    #[test]
+    fn each_function_is_verified_once() {
+        // verified once per closure made from it, this takes 200^5 passes
+        let mut inner = None;
+        for level in 0..5 {
+            let mut function = Function::new(Some(format!("f{}", level)));
+            function.chunk = closures_of(inner, 200);
+            inner = Some(function);
+        }
+        assert_eq!(closures_of(inner, 200).verify(), Ok(()));
+    }
+
+    #[test]
+    fn recursive_local_functions_survive_loxc() {
+        let source = "
+            fun outer() {
+                fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+                return fib(10);
+            }
+            var result;
+            {
+                fun count(n) { if (n > 0) return count(n - 1); return outer(); }
+                result = count(3);
+            }
+        ";
+        for opt_level in 0..=2 {
+            assert_eq!(run_compiled(source, opt_level), Value::int_val(55));
+        }
+    }
+}
@@ -1,21 +1,87 @@
-use crate::chunk::OpCode::OP_NIL;
 use crate::chunk::{Chunk, OpCode};
-use crate::compiler::{Compiler, Parser};
-use crate::debug;
+use crate::compiler::ScriptMode::{MODE_ECHO, MODE_EVAL, MODE_RUN};
+use crate::compiler::{Compiler, Global, Parser, ScriptMode};
+#[cfg(feature = "debug_trace_execution")]
 use crate::debug::disassemble_instruction;
+use crate::object::{Closure, Function, HostClass, LoxClass, Module, Obj, Upvalue};
 use crate::scanner::Scanner;
-use crate::value::{print_value, values_equal, Value};
-use crate::vm::InterpretResult::{INTERPRET_OK, INTERPRET_RUNTIME_ERROR};
-use std::collections::HashMap;
+use crate::value::{values_equal, Value};
+use crate::vm::InterpretResult::{INTERPRET_COMPILE_ERROR, INTERPRET_OK, INTERPRET_RUNTIME_ERROR};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fmt::Display;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
-const STACK_MAX: usize = 256;
+/// the default call depth limit
+const FRAMES_MAX: usize = 64;
+/// the default stack size limit, in slots
+const STACK_MAX: usize = FRAMES_MAX * 256;
 
-pub struct VM {
-    chunk: Chunk,
+/// an ongoing function call
+struct CallFrame {
+    closure: Rc<Closure>,
     ip_index: usize,
+    /// stack index of the frame's slot zero
+    slots: usize,
+    /// the body of an imported module, whose return evaluates to the module
+    is_module: bool,
+}
+
+/// where a VM writes, stdout and stderr by default, and how deep scripts may go
+pub struct VMConfig {
+    /// what `print` and the REPL echo write
+    pub out: Box<dyn Write>,
+    /// compile errors, warnings and runtime errors with their stack trace
+    pub err: Box<dyn Write>,
+    /// calls nested deeper than this fail with "Stack overflow."
+    pub max_frames: usize,
+    /// a call made with more value stack slots in use than this fails with "Stack overflow.",
+    /// the stack grows on demand up to about this size
+    pub max_stack: usize,
+    /// 0 runs the bytecode as the compiler emits it, 1 applies peephole optimizations,
+    /// 2 (the default) also fuses common sequences into superinstructions
+    pub opt_level: u8,
+}
+
+impl Default for VMConfig {
+    fn default() -> Self {
+        Self {
+            out: Box::new(std::io::stdout()),
+            err: Box::new(std::io::stderr()),
+            max_frames: FRAMES_MAX,
+            max_stack: STACK_MAX,
+            opt_level: 2,
+        }
+    }
+}
+
+pub struct VM {
+    frames: Vec<CallFrame>,
+    /// grows as needed, the last value is the top
     stack: Vec<Value>,
-    stack_top: usize,
-    globals: HashMap<String, Value>,
+    max_frames: usize,
+    max_stack: usize,
+    opt_level: u8,
+    /// the module of the script passed to `interpret`, shared by every REPL line
+    main: Rc<Module>,
+    /// what the compiler knows about the globals of `main`, so constness survives across
+    /// REPL lines
+    global_symbols: HashMap<String, Global>,
+    /// host classes, seen by every module unless one of its globals has the same name
+    builtins: HashMap<String, Value>,
+    /// modules that finished loading, by canonical path
+    modules: HashMap<PathBuf, Rc<Module>>,
+    /// modules whose top-level code is running, innermost last
+    loading: Vec<Rc<Module>>,
+    /// upvalues still pointing into the stack, ordered by slot
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    out: Box<dyn Write>,
+    err: Box<dyn Write>,
+    /// the error behind the last `INTERPRET_COMPILE_ERROR` or `INTERPRET_RUNTIME_ERROR`
+    error: Option<LoxError>,
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -25,78 +91,349 @@ pub enum InterpretResult {
     INTERPRET_RUNTIME_ERROR,
 }
 
+/// why `eval`, `call` or `set_global` failed, also written to the error sink as it happens
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoxError {
+    /// the compiler's diagnostics, one line each
+    compile(Vec<String>),
+    /// the message and the stack trace, innermost call first
+    runtime { message: String, trace: Vec<String> },
+    /// a `Value` did not hold the Rust type asked for
+    conversion(String),
+}
+
+impl Display for LoxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoxError::compile(diagnostics) => write!(f, "{}", diagnostics.join("\n")),
+            LoxError::runtime { message, trace } => {
+                write!(f, "{}", message)?;
+                for line in trace {
+                    write!(f, "\n{}", line)?;
+                }
+                Ok(())
+            }
+            LoxError::conversion(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for LoxError {}
+
 // TODO refact , BINARY_OP_NUM_TYPE, BINARY_OP_BOOL_TYPE
+/// two ints give an int through the checked `$int_op`, anything else a float
 macro_rules! BINARY_OP_NUM_TYPE {
-    ($op:tt, $self:expr) => {
+    ($op:tt, $int_op:ident, $self:expr) => {
         {
-
-            if !$self.peek(0).is_number() || !$self.peek(1).is_number() {
+            let (a, b) = $self.operands();
+            if !a.is_number() || !b.is_number() {
                 $self.runtime_error("Operands must be numbers.");
                 return INTERPRET_RUNTIME_ERROR;
             }
-            let b = $self.pop().as_number();
-            let a = $self.pop().as_number();
-            $self.push(Value::number_val(a $op b));
+            let value = if a.is_int() && b.is_int() {
+                a.as_int().$int_op(b.as_int()).map(Value::int_val)
+            } else {
+                Some(Value::number_val(a.as_number() $op b.as_number()))
+            };
+            let Some(value) = value else {
+                $self.runtime_error("Integer overflow.");
+                return INTERPRET_RUNTIME_ERROR;
+            };
+            $self.replace_operands(value);
         }
     };
 }
 
-macro_rules! BINARY_OP_BOOL_TYPE {
+macro_rules! BINARY_OP_INT_TYPE {
     ($op:tt, $self:expr) => {
         {
+            let (a, b) = $self.operands();
+            if !a.is_int() || !b.is_int() {
+                $self.runtime_error("Operands must be integers.");
+                return INTERPRET_RUNTIME_ERROR;
+            }
+            let value = Value::int_val(a.as_int() $op b.as_int());
+            $self.replace_operands(value);
+        }
+    };
+}
+
+/// shifts by a negative amount or by 64 bits or more are errors rather than wrapping
+macro_rules! SHIFT_OP {
+    ($shift:ident, $self:expr) => {{
+        let (a, b) = $self.operands();
+        if !a.is_int() || !b.is_int() {
+            $self.runtime_error("Operands must be integers.");
+            return INTERPRET_RUNTIME_ERROR;
+        }
+        let (a, b) = (a.as_int(), b.as_int());
+        let Some(value) = u32::try_from(b).ok().and_then(|b| a.$shift(b)) else {
+            $self.runtime_error("Shift amount must be between 0 and 63.");
+            return INTERPRET_RUNTIME_ERROR;
+        };
+        $self.replace_operands(Value::int_val(value));
+    }};
+}
+
+/// `+` on the two values on top of the stack
+macro_rules! ADD {
+    ($self:expr) => {{
+        let (a, b) = $self.operands();
+        if a.is_string() && b.is_string() {
+            $self.concatenate();
+        } else if a.is_number() && b.is_number() {
+            BINARY_OP_NUM_TYPE!(+, checked_add, $self);
+        } else {
+            $self.runtime_error("Operands must be two numbers or two strings.");
+            return INTERPRET_RUNTIME_ERROR;
+        }
+    }};
+}
 
-            if !$self.peek(0).is_number() || !$self.peek(1).is_number() {
+/// `!` before the operator negates the comparison, which is how `>=` and `<=` are defined
+macro_rules! BINARY_OP_BOOL_TYPE {
+    (! $op:tt, $self:expr) => {{
+        let (a, b) = $self.operands();
+        if !a.is_number() || !b.is_number() {
+            $self.runtime_error("Operands must be numbers.");
+            return INTERPRET_RUNTIME_ERROR;
+        }
+        // negated rather than flipped, so NaN compares true as it did before fusing
+        #[allow(clippy::neg_cmp_op_on_partial_ord)]
+        let value = if a.is_int() && b.is_int() {
+            !(a.as_int() $op b.as_int())
+        } else {
+            !(a.as_number() $op b.as_number())
+        };
+        $self.replace_operands(Value::bool_val(value));
+    }};
+    ($op:tt, $self:expr) => {
+        {
+            let (a, b) = $self.operands();
+            if !a.is_number() || !b.is_number() {
                 $self.runtime_error("Operands must be numbers.");
                 return INTERPRET_RUNTIME_ERROR;
             }
-            let b = $self.pop().as_number();
-            let a = $self.pop().as_number();
-            $self.push(Value::bool_val(a $op b));
+            let value = if a.is_int() && b.is_int() {
+                a.as_int() $op b.as_int()
+            } else {
+                a.as_number() $op b.as_number()
+            };
+            $self.replace_operands(Value::bool_val(value));
         }
     };
 }
 
 impl VM {
-    pub fn new(chunk: Chunk) -> Self {
+    pub fn new(config: VMConfig) -> Self {
         Self {
-            chunk,
-            ip_index: 0,
-            stack: vec![Value::default(); STACK_MAX],
-            stack_top: 0,
-            globals: HashMap::new(),
+            frames: vec![],
+            stack: vec![],
+            max_frames: config.max_frames,
+            max_stack: config.max_stack,
+            opt_level: config.opt_level,
+            main: Rc::new(Module::new("script".into(), PathBuf::new(), HashSet::new())),
+            global_symbols: HashMap::new(),
+            builtins: HashMap::new(),
+            modules: HashMap::new(),
+            loading: vec![],
+            open_upvalues: vec![],
+            out: config.out,
+            err: config.err,
+            error: None,
         }
     }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("no active call frame")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("no active call frame")
+    }
+
+    fn chunk(&self) -> &Chunk {
+        &self.frame().closure.function.chunk
+    }
+
     fn ip(&self) -> u8 {
-        self.chunk.codes[self.ip_index]
+        self.chunk().codes[self.frame().ip_index]
+    }
+
+    fn globals(&self) -> &RefCell<HashMap<String, Value>> {
+        &self.frame().closure.module.globals
+    }
+
+    /// the doc comment of a global declared by an earlier `interpret`
+    pub fn doc(&self, name: &str) -> Option<&str> {
+        self.global_symbols.get(name)?.doc.as_deref()
+    }
+
+    /// interpret a script file, its imports are resolved relative to `path`. The globals of
+    /// the main module carry over
+    pub fn interpret_file(&mut self, source: &str, path: &Path) -> InterpretResult {
+        self.set_main(path);
+        self.interpret(source)
+    }
+
+    /// run a script compiled ahead of time, loaded from the `.loxc` file at `path`.
+    /// The chunk is verified first, a malformed one is a compile error
+    pub fn interpret_chunk(&mut self, chunk: Chunk, path: &Path) -> InterpretResult {
+        if let Err(message) = chunk.verify() {
+            self.report(std::slice::from_ref(&message));
+            self.error = Some(LoxError::compile(vec![message]));
+            return INTERPRET_COMPILE_ERROR;
+        }
+        self.set_main(path);
+        let mut function = Function::new(None);
+        function.chunk = chunk;
+        return self.run_script(function, MODE_RUN);
+    }
+
+    /// compile a script without running it, for saving with `Chunk::serialize`
+    pub fn compile(&mut self, source: &str) -> Result<Chunk, LoxError> {
+        match self.compile_script(source, MODE_RUN) {
+            Some(function) => Ok(function.chunk),
+            None => Err(self.error.take().expect("compile error was not recorded")),
+        }
+    }
+
+    fn set_main(&mut self, path: &Path) {
+        let name = path.display().to_string();
+        let path = std::fs::canonicalize(path).unwrap_or(path.to_path_buf());
+        let mut main = Module::new(name, path, HashSet::new());
+        // what the host set and earlier runs defined stays, for the closures of those runs too
+        main.globals = self.main.globals.clone();
+        self.main = Rc::new(main);
     }
+
     pub fn interpret(&mut self, source: &str) -> InterpretResult {
+        return self.compile_and_run(source, MODE_RUN);
+    }
+
+    /// like `interpret`, but top-level expression statements echo their value
+    pub fn interpret_line(&mut self, source: &str) -> InterpretResult {
+        return self.compile_and_run(source, MODE_ECHO);
+    }
+
+    /// run `source` in the main module, the result is the value of its last top-level
+    /// expression statement, or nil
+    pub fn eval(&mut self, source: &str) -> Result<Value, LoxError> {
+        let result = self.compile_and_run(source, MODE_EVAL);
+        return self.take_result(result);
+    }
+
+    /// call the global function `name` of the main module
+    pub fn call(&mut self, name: &str, args: &[Value]) -> Result<Value, LoxError> {
+        let Some(callee) = self.get_global(name) else {
+            self.runtime_error(&format!("Undefined variable '{}'.", name));
+            return Err(self.error.take().expect("runtime error was not recorded"));
+        };
+        self.push(callee.clone());
+        for arg in args {
+            self.push(arg.clone());
+        }
+        let frames = self.frames.len();
+        let result = if !self.call_value(callee, args.len(), &[]) {
+            INTERPRET_RUNTIME_ERROR
+        } else if self.frames.len() > frames {
+            self.run()
+        } else {
+            // a host class builds its instance in place, there is no frame to run
+            INTERPRET_OK
+        };
+        return self.take_result(result);
+    }
+
+    /// bind the host class `T` to the name `T::NAME` in every module
+    pub fn register_class<T: LoxClass>(&mut self) {
+        let class = Value::obj(Obj::class(Rc::new(HostClass::new::<T>())));
+        self.builtins.insert(T::NAME.to_string(), class);
+    }
+
+    /// a global of the main module, or a registered host class
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        let value = self.main.globals.borrow().get(name).cloned();
+        value.or_else(|| self.builtins.get(name).cloned())
+    }
+
+    /// define or overwrite a global of the main module, constants can't be assigned
+    pub fn set_global(&mut self, name: &str, value: impl Into<Value>) -> Result<(), LoxError> {
+        if self.global_symbols.get(name).is_some_and(|g| g.is_const) {
+            self.runtime_error("Can't assign to a constant.");
+            return Err(self.error.take().expect("runtime error was not recorded"));
+        }
+        self.main
+            .globals
+            .borrow_mut()
+            .insert(name.to_string(), value.into());
+        return Ok(());
+    }
+
+    /// a finished run leaves its result on the stack
+    fn take_result(&mut self, result: InterpretResult) -> Result<Value, LoxError> {
+        match result {
+            INTERPRET_OK => Ok(self.pop()),
+            _ => Err(self.error.take().expect("error was not recorded")),
+        }
+    }
+
+    fn compile_and_run(&mut self, source: &str, mode: ScriptMode) -> InterpretResult {
+        let Some(function) = self.compile_script(source, mode) else {
+            return INTERPRET_COMPILE_ERROR;
+        };
+        return self.run_script(function, mode);
+    }
+
+    fn compile_script(&mut self, source: &str, mode: ScriptMode) -> Option<Function> {
         let chunk = Chunk::new();
         let scanner = Scanner::new(source);
         let parser = Parser::new(Default::default(), Default::default());
         let mut compiler: Compiler = Compiler::new(parser, scanner, chunk);
-        if !compiler.compile() {}
-        // self.chunk = chunk;
-        INTERPRET_OK
+        compiler.globals = self.global_symbols.clone();
+        compiler.mode = mode;
+        compiler.opt_level = self.opt_level;
+        let function = compiler.compile();
+        self.report(&compiler.diagnostics);
+        let Some(function) = function else {
+            self.error = Some(LoxError::compile(compiler.diagnostics));
+            return None;
+        };
+        self.global_symbols = compiler.globals;
+        return Some(function);
+    }
+
+    fn run_script(&mut self, function: Function, mode: ScriptMode) -> InterpretResult {
+        let closure = Rc::new(Closure::new(Rc::new(function), self.main.clone()));
+        self.push(Value::obj(Obj::closure(closure.clone())));
+        if !self.call_closure(closure, 0, &[]) {
+            return INTERPRET_RUNTIME_ERROR;
+        }
+        let result = self.run();
+        if mode != MODE_EVAL && result == INTERPRET_OK {
+            self.pop();
+        }
+        return result;
     }
 
     fn run(&mut self) -> InterpretResult {
         loop {
-            print!("          ");
-            for slot in &self.stack[0..self.stack_top] {
-                print!("[ ");
-                print_value(slot.clone());
-                print!(" ]");
+            #[cfg(feature = "debug_trace_execution")]
+            {
+                print!("          ");
+                for slot in &self.stack {
+                    print!("[ {} ]", slot.repr());
+                }
+                print!("\n");
+                disassemble_instruction(self.chunk(), self.frame().ip_index);
             }
-            print!("\n");
-            disassemble_instruction(&self.chunk, self.ip_index);
             let instruction = self.read_byte();
 
             match OpCode::try_from(instruction) {
                 Ok(instruction) => match instruction {
-                    OpCode::OP_CONSTANT => {
-                        let constant = self.read_constant();
+                    OpCode::OP_CONSTANT | OpCode::OP_CONSTANT_LONG => {
+                        let constant = self.read_constant(instruction);
                         self.push(constant);
-                        print!("\n");
                     }
                     OpCode::OP_NIL => self.push(Value::nil_val()),
                     OpCode::OP_TRUE => self.push(Value::bool_val(true)),
@@ -104,79 +441,342 @@ impl VM {
                     OpCode::OP_POP => {
                         self.pop();
                     }
-                    OpCode::OP_SET_LOCAL => {
+                    OpCode::OP_DUP => self.push(self.peek(0).clone()),
+                    OpCode::OP_SET_LOCAL | OpCode::OP_SET_LOCAL_LONG => {
+                        let slot = self.frame().slots + self.read_index(instruction);
+                        self.stack[slot] = self.peek(0).clone();
+                    }
+                    OpCode::OP_GET_LOCAL | OpCode::OP_GET_LOCAL_LONG => {
+                        let slot = self.frame().slots + self.read_index(instruction);
+                        self.push(self.stack[slot].clone());
+                    }
+                    OpCode::OP_GET_UPVALUE => {
                         let slot = self.read_byte() as usize;
-                        self.stack[slot] = self.peek(0);
+                        let upvalue = self.frame().closure.upvalues[slot].clone();
+                        let value = match &*upvalue.borrow() {
+                            Upvalue::open(index) => self.stack[*index].clone(),
+                            Upvalue::closed(value) => value.clone(),
+                        };
+                        self.push(value);
                     }
-                    OpCode::OP_GET_LOCAL => {
+                    OpCode::OP_SET_UPVALUE => {
                         let slot = self.read_byte() as usize;
-                        self.push(self.stack[slot].clone());
+                        let upvalue = self.frame().closure.upvalues[slot].clone();
+                        let value = self.peek(0).clone();
+                        let mut upvalue = upvalue.borrow_mut();
+                        match &mut *upvalue {
+                            Upvalue::open(index) => self.stack[*index] = value,
+                            Upvalue::closed(closed) => *closed = value,
+                        }
                     }
-                    OpCode::OP_GET_GLOBAL => {
-                        let name = self.read_string();
-                        let Some(value) = self.globals.get(&name) else {
-                            self.runtime_error(&format!("Undefined variable '{:?}'.", name));
+                    OpCode::OP_GET_GLOBAL | OpCode::OP_GET_GLOBAL_LONG => {
+                        let name = self.read_string(instruction);
+                        let value = self.globals().borrow().get(&name).cloned();
+                        let Some(value) = value.or_else(|| self.builtins.get(&name).cloned())
+                        else {
+                            self.runtime_error(&format!("Undefined variable '{}'.", name));
                             return INTERPRET_RUNTIME_ERROR;
                         };
-                        self.push(value.clone());
+                        self.push(value);
                     }
-                    OpCode::OP_DEFINE_GLOBAL => {
-                        let name = self.read_string();
-                        self.globals.insert(name, self.peek(0));
-                        self.pop();
+                    OpCode::OP_DEFINE_GLOBAL | OpCode::OP_DEFINE_GLOBAL_LONG => {
+                        let name = self.read_string(instruction);
+                        let value = self.pop();
+                        self.globals().borrow_mut().insert(name, value);
                     }
-                    OpCode::OP_SET_GLOBAL => {
-                        let name = self.read_string();
-                        if self.globals.insert(name.clone(), self.peek(0)).is_none() {
-                            self.globals.remove(&name);
-                            self.runtime_error(&format!("Undefined variable '{:?}'.", name));
+                    OpCode::OP_SET_GLOBAL | OpCode::OP_SET_GLOBAL_LONG => {
+                        let name = self.read_string(instruction);
+                        let value = self.peek(0).clone();
+                        let defined = match self.globals().borrow_mut().get_mut(&name) {
+                            Some(global) => {
+                                *global = value;
+                                true
+                            }
+                            None => false,
+                        };
+                        if !defined {
+                            self.runtime_error(&format!("Undefined variable '{}'.", name));
                             return INTERPRET_RUNTIME_ERROR;
                         }
                     }
                     OpCode::OP_EQUAL => {
-                        let b = self.pop();
-                        let a = self.pop();
-                        self.push(Value::bool_val(values_equal(a, b)))
+                        let (a, b) = self.operands();
+                        self.replace_operands(Value::bool_val(values_equal(a, b)))
+                    }
+                    OpCode::OP_NOT_EQUAL => {
+                        let (a, b) = self.operands();
+                        self.replace_operands(Value::bool_val(!values_equal(a, b)))
                     }
                     OpCode::OP_GREATER => BINARY_OP_BOOL_TYPE!(>, self),
                     OpCode::OP_LESS => BINARY_OP_BOOL_TYPE!(<, self),
-                    OpCode::OP_ADD => {
-                        if self.peek(0).is_string() && self.peek(1).is_string() {
-                            self.concatenate();
-                        } else if self.peek(0).is_number() && self.peek(1).is_number() {
-                            let b = self.pop().as_number();
-                            let a = self.pop().as_number();
-                            self.push(Value::number(a + b));
+                    OpCode::OP_GREATER_EQUAL => BINARY_OP_BOOL_TYPE!(!<, self),
+                    OpCode::OP_LESS_EQUAL => BINARY_OP_BOOL_TYPE!(!>, self),
+                    OpCode::OP_ADD => ADD!(self),
+                    OpCode::OP_ADD_LOCAL_CONST => {
+                        let slot = self.frame().slots + self.read_byte() as usize;
+                        let constant = self.read_constant(OpCode::OP_CONSTANT);
+                        let local = &self.stack[slot];
+                        let sum = if local.is_int() && constant.is_int() {
+                            local.as_int().checked_add(constant.as_int())
+                        } else {
+                            None
+                        };
+                        if let Some(sum) = sum {
+                            self.push(Value::int_val(sum));
+                        } else {
+                            self.push(self.stack[slot].clone());
+                            self.push(constant);
+                            ADD!(self);
+                        }
+                    }
+                    OpCode::OP_INC_LOCAL => {
+                        let slot = self.frame().slots + self.read_byte() as usize;
+                        let local = &self.stack[slot];
+                        if local.is_int() && local.as_int() < i64::MAX {
+                            self.stack[slot] = Value::int_val(local.as_int() + 1);
+                        } else {
+                            self.push(self.stack[slot].clone());
+                            self.push(Value::int_val(1));
+                            ADD!(self);
+                            self.stack[slot] = self.pop();
+                        }
+                    }
+                    OpCode::OP_LESS_LOCALS_JUMP => {
+                        let slots = self.frame().slots;
+                        let a = slots + self.read_byte() as usize;
+                        let b = slots + self.read_byte() as usize;
+                        let offset = self.read_short();
+                        let (a, b) = (&self.stack[a], &self.stack[b]);
+                        if !a.is_number() || !b.is_number() {
+                            self.runtime_error("Operands must be numbers.");
+                            return INTERPRET_RUNTIME_ERROR;
+                        }
+                        let less = if a.is_int() && b.is_int() {
+                            a.as_int() < b.as_int()
                         } else {
-                            self.runtime_error("Operands must be two numbers or two strings.");
+                            a.as_number() < b.as_number()
+                        };
+                        if !less {
+                            self.frame_mut().ip_index += offset as usize;
+                        }
+                    }
+                    OpCode::OP_SUBTRACT => BINARY_OP_NUM_TYPE!( -, checked_sub, self),
+                    OpCode::OP_MULTIPLY => BINARY_OP_NUM_TYPE!( *, checked_mul, self),
+                    OpCode::OP_DIVIDE => {
+                        if !self.check_int_divisor() {
+                            return INTERPRET_RUNTIME_ERROR;
+                        }
+                        BINARY_OP_NUM_TYPE!(/, checked_div, self)
+                    }
+                    OpCode::OP_MODULO => {
+                        if !self.check_int_divisor() {
                             return INTERPRET_RUNTIME_ERROR;
                         }
+                        BINARY_OP_NUM_TYPE!(%, checked_rem, self)
+                    }
+                    OpCode::OP_BIT_AND => BINARY_OP_INT_TYPE!(&, self),
+                    OpCode::OP_BIT_OR => BINARY_OP_INT_TYPE!(|, self),
+                    OpCode::OP_BIT_XOR => BINARY_OP_INT_TYPE!(^, self),
+                    OpCode::OP_SHIFT_LEFT => SHIFT_OP!(checked_shl, self),
+                    OpCode::OP_SHIFT_RIGHT => SHIFT_OP!(checked_shr, self),
+                    OpCode::OP_BIT_NOT => {
+                        if !self.peek(0).is_int() {
+                            self.runtime_error("Operand must be an integer.");
+                            return INTERPRET_RUNTIME_ERROR;
+                        }
+                        let value = !self.peek(0).as_int();
+                        self.replace_top(Value::int_val(value));
                     }
-                    OpCode::OP_SUBTRACT => BINARY_OP_NUM_TYPE!( -, self),
-                    OpCode::OP_MULTIPLY => BINARY_OP_NUM_TYPE!( *, self),
-                    OpCode::OP_DIVIDE => BINARY_OP_NUM_TYPE!(/, self),
                     OpCode::OP_NOT => {
-                        let v = self.pop();
-                        self.push(Value::bool_val(self.is_falsey(v)))
+                        let value = self.is_falsey(self.peek(0));
+                        self.replace_top(Value::bool_val(value))
                     }
                     OpCode::OP_NEGATE => {
                         if !self.peek(0).is_number() {
                             self.runtime_error("Operand must be a number.");
                             return INTERPRET_RUNTIME_ERROR;
                         }
-                        let value = -self.pop().as_number();
-                        self.push(Value::number_val(value));
+                        let value = self.peek(0);
+                        if !value.is_int() {
+                            let negated = -value.as_number();
+                            self.replace_top(Value::number_val(negated));
+                        } else if let Some(negated) = value.as_int().checked_neg() {
+                            self.replace_top(Value::int_val(negated));
+                        } else {
+                            self.runtime_error("Integer overflow.");
+                            return INTERPRET_RUNTIME_ERROR;
+                        }
                     }
                     OpCode::OP_PRINT => {
-                        print_value(self.pop());
-                        print!("\n");
+                        let value = self.pop();
+                        let _ = writeln!(self.out, "{}", value);
+                    }
+                    OpCode::OP_ECHO => {
+                        let value = self.pop();
+                        if !value.is_nil() {
+                            let _ = writeln!(self.out, "{}", value.repr());
+                        }
+                    }
+                    OpCode::OP_JUMP => {
+                        let offset = self.read_short();
+                        self.frame_mut().ip_index += offset as usize;
+                    }
+                    OpCode::OP_JUMP_IF_FALSE => {
+                        let offset = self.read_short();
+                        if self.is_falsey(self.peek(0)) {
+                            self.frame_mut().ip_index += offset as usize;
+                        }
+                    }
+                    OpCode::OP_JUMP_IF_NOT_NIL => {
+                        let offset = self.read_short();
+                        if !self.peek(0).is_nil() {
+                            self.frame_mut().ip_index += offset as usize;
+                        }
+                    }
+                    OpCode::OP_LOOP => {
+                        let offset = self.read_short();
+                        self.frame_mut().ip_index -= offset as usize;
+                    }
+                    OpCode::OP_JUMP_TABLE => {
+                        let min = self.read_short() as i16 as f64;
+                        let count = self.read_byte() as usize;
+                        let table = self.frame().ip_index;
+                        let table_end = table + 2 * (count + 1);
+                        let value = self.pop();
+                        // the default entry follows the case entries
+                        let mut entry = count;
+                        if value.is_number() {
+                            let index = value.as_number() - min;
+                            if index >= 0.0 && index < count as f64 && index.fract() == 0.0 {
+                                entry = index as usize;
+                            }
+                        }
+                        self.frame_mut().ip_index = table + 2 * entry;
+                        let offset = self.read_short();
+                        self.frame_mut().ip_index = table_end - offset as usize;
+                    }
+                    OpCode::OP_CALL => {
+                        let arg_count = self.read_byte() as usize;
+                        if !self.call_value(self.peek(arg_count).clone(), arg_count, &[]) {
+                            return INTERPRET_RUNTIME_ERROR;
+                        }
+                    }
+                    OpCode::OP_CALL_KW | OpCode::OP_CALL_KW_LONG => {
+                        let arg_count = self.read_byte() as usize;
+                        let keyword_count = self.read_byte() as usize;
+                        let keywords: Vec<String> = (0..keyword_count)
+                            .map(|_| self.read_string(instruction))
+                            .collect();
+                        if !self.call_value(self.peek(arg_count).clone(), arg_count, &keywords) {
+                            return INTERPRET_RUNTIME_ERROR;
+                        }
+                    }
+                    OpCode::OP_INVOKE | OpCode::OP_INVOKE_LONG => {
+                        let name = self.read_string(instruction);
+                        let arg_count = self.read_byte() as usize;
+                        if !self.invoke(&name, arg_count, &[]) {
+                            return INTERPRET_RUNTIME_ERROR;
+                        }
+                    }
+                    OpCode::OP_INVOKE_KW | OpCode::OP_INVOKE_KW_LONG => {
+                        let name = self.read_string(instruction);
+                        let arg_count = self.read_byte() as usize;
+                        let keyword_count = self.read_byte() as usize;
+                        let keywords: Vec<String> = (0..keyword_count)
+                            .map(|_| self.read_string(instruction))
+                            .collect();
+                        if !self.invoke(&name, arg_count, &keywords) {
+                            return INTERPRET_RUNTIME_ERROR;
+                        }
+                    }
+                    OpCode::OP_IMPORT | OpCode::OP_IMPORT_LONG => {
+                        let path = self.read_string(instruction);
+                        if !self.import(path) {
+                            return INTERPRET_RUNTIME_ERROR;
+                        }
+                    }
+                    OpCode::OP_GET_PROPERTY | OpCode::OP_GET_PROPERTY_LONG => {
+                        let name = self.read_string(instruction);
+                        let receiver = self.pop();
+                        let Some(value) = self.property(&receiver, &name) else {
+                            return INTERPRET_RUNTIME_ERROR;
+                        };
+                        self.push(value);
+                    }
+                    OpCode::OP_GET_INDEX => {
+                        let index = self.pop();
+                        let list = self.pop();
+                        let Some(Obj::list(items)) = list.as_obj() else {
+                            self.runtime_error("Only lists can be indexed.");
+                            return INTERPRET_RUNTIME_ERROR;
+                        };
+                        if !index.is_number() || index.as_number().fract() != 0.0 {
+                            self.runtime_error("List index must be an integer.");
+                            return INTERPRET_RUNTIME_ERROR;
+                        }
+                        let item = items.borrow().get(index.as_number() as usize).cloned();
+                        let Some(item) = item.filter(|_| index.as_number() >= 0.0) else {
+                            self.runtime_error("List index out of range.");
+                            return INTERPRET_RUNTIME_ERROR;
+                        };
+                        self.push(item);
+                    }
+                    OpCode::OP_MATCH_LIST => {
+                        let length = self.read_byte() as usize;
+                        let matches = match self.peek(0).as_obj() {
+                            Some(Obj::list(items)) => items.borrow().len() == length,
+                            _ => false,
+                        };
+                        self.replace_top(Value::bool_val(matches));
+                    }
+                    OpCode::OP_CLOSURE | OpCode::OP_CLOSURE_LONG => {
+                        let constant = self.read_constant(instruction);
+                        let Some(Obj::function(function)) = constant.as_obj() else {
+                            unreachable!("OP_CLOSURE operand is not a function");
+                        };
+                        let module = self.frame().closure.module.clone();
+                        let mut closure = Closure::new(function.clone(), module);
+                        for _ in 0..closure.function.upvalue_count {
+                            let is_local = self.read_byte() == 1;
+                            let index = self.read_short() as usize;
+                            let upvalue = if is_local {
+                                self.capture_upvalue(self.frame().slots + index)
+                            } else {
+                                self.frame().closure.upvalues[index].clone()
+                            };
+                            closure.upvalues.push(upvalue);
+                        }
+                        self.push(Value::obj(Obj::closure(Rc::new(closure))));
+                    }
+                    OpCode::OP_CLOSE_UPVALUE => {
+                        self.close_upvalues(self.stack.len() - 1);
+                        self.pop();
                     }
                     OpCode::OP_RETURN => {
-                        // Exit interpreter.
-                        return INTERPRET_OK;
+                        let result = self.pop();
+                        let frame = self.frames.pop().expect("no active call frame");
+                        self.close_upvalues(frame.slots);
+                        if self.frames.is_empty() {
+                            // the caller of `run` takes the result
+                            self.stack.truncate(frame.slots);
+                            self.push(result);
+                            return INTERPRET_OK;
+                        }
+                        self.stack.truncate(frame.slots);
+                        if frame.is_module {
+                            // a module finished running, its import evaluates to the module
+                            let module = self.loading.pop().expect("no module is loading");
+                            self.modules.insert(module.path.clone(), module.clone());
+                            self.push(Value::obj(Obj::module(module)));
+                        } else {
+                            self.push(result);
+                        }
                     }
                 },
-                Err(_) => {}
+                Err(_) => {
+                    self.runtime_error(&format!("Unknown opcode {}.", instruction));
+                    return INTERPRET_RUNTIME_ERROR;
+                }
             }
         }
     }
@@ -184,55 +784,435 @@ impl VM {
     /// read byte from constant pool by current ip
     fn read_byte(&mut self) -> u8 {
         let ip = self.ip();
-        self.ip_index += 1;
+        self.frame_mut().ip_index += 1;
         ip
     }
 
-    fn read_constant(&mut self) -> Value {
-        let index = self.read_byte() as usize;
-        self.chunk.constants.values[index].clone()
+    /// read a 16-bit big-endian operand, used by jump instructions
+    fn read_short(&mut self) -> u16 {
+        let high = self.read_byte() as u16;
+        let low = self.read_byte() as u16;
+        (high << 8) | low
+    }
+
+    /// read a 24-bit big-endian operand, the constant index of the `_LONG` instructions
+    fn read_long(&mut self) -> usize {
+        let high = self.read_short() as usize;
+        let low = self.read_byte() as usize;
+        (high << 8) | low
+    }
+
+    /// read the constant or slot operand of `instruction`, one byte wide unless it is a
+    /// `_LONG` form
+    fn read_index(&mut self, instruction: OpCode) -> usize {
+        if instruction.is_long() {
+            self.read_long()
+        } else {
+            self.read_byte() as usize
+        }
+    }
+
+    fn read_constant(&mut self, instruction: OpCode) -> Value {
+        let index = self.read_index(instruction);
+        self.chunk().constants.values[index].clone()
+    }
+
+    /// push the module at `path`, running its top-level code first if it isn't loaded yet
+    fn import(&mut self, path: String) -> bool {
+        let importer = self.frame().closure.module.path.clone();
+        let directory = importer.parent().unwrap_or(Path::new(""));
+        let Ok(canonical) = std::fs::canonicalize(directory.join(&path)) else {
+            self.runtime_error(&format!("Could not find module '{}'.", path));
+            return false;
+        };
+        if let Some(module) = self.modules.get(&canonical) {
+            self.push(Value::obj(Obj::module(module.clone())));
+            return true;
+        }
+        let running: Vec<&Rc<Module>> = std::iter::once(&self.main).chain(&self.loading).collect();
+        if let Some(start) = running.iter().position(|module| module.path == canonical) {
+            let mut cycle: Vec<&str> = running[start..].iter().map(|m| m.name.as_str()).collect();
+            cycle.push(&path);
+            let message = format!("Import cycle: {}.", cycle.join(" -> "));
+            self.runtime_error(&message);
+            return false;
+        }
+        let Ok(source) = std::fs::read_to_string(&canonical) else {
+            self.runtime_error(&format!("Could not read module '{}'.", path));
+            return false;
+        };
+
+        let scanner = Scanner::new(&source);
+        let parser = Parser::new(Default::default(), Default::default());
+        let mut compiler = Compiler::new(parser, scanner, Chunk::new());
+        compiler.opt_level = self.opt_level;
+        let function = compiler.compile();
+        self.report(&compiler.diagnostics);
+        let Some(function) = function else {
+            self.runtime_error(&format!("Could not compile module '{}'.", path));
+            return false;
+        };
+        let module = Rc::new(Module::new(path, canonical, compiler.exports));
+        self.loading.push(module.clone());
+        let closure = Rc::new(Closure::new(Rc::new(function), module));
+        self.push(Value::obj(Obj::closure(closure.clone())));
+        if !self.call_closure(closure, 0, &[]) {
+            return false;
+        }
+        self.frame_mut().is_module = true;
+        return true;
+    }
+
+    /// `keywords` names the last `keywords.len()` of the `arg_count` arguments
+    fn call_value(&mut self, callee: Value, arg_count: usize, keywords: &[String]) -> bool {
+        match callee.as_obj() {
+            Some(Obj::closure(closure)) => {
+                return self.call_closure(closure.clone(), arg_count, keywords);
+            }
+            Some(Obj::class(class)) => {
+                if !keywords.is_empty() {
+                    self.runtime_error(&format!("{}() takes no keyword arguments.", class.name));
+                    return false;
+                }
+                let first = self.stack.len() - arg_count;
+                let instance = match class.instantiate(&self.stack[first..]) {
+                    Ok(instance) => instance,
+                    Err(message) => {
+                        self.runtime_error(&message);
+                        return false;
+                    }
+                };
+                self.stack.truncate(first - 1);
+                self.push(Value::obj(Obj::instance(Rc::new(instance))));
+                return true;
+            }
+            _ => {}
+        }
+        self.runtime_error("Can only call functions and classes.");
+        return false;
+    }
+
+    /// the value of `receiver.name`, reports a runtime error when there is none
+    fn property(&mut self, receiver: &Value, name: &str) -> Option<Value> {
+        let value = match receiver.as_obj() {
+            Some(Obj::module(module)) => module.export(name),
+            Some(Obj::instance(instance)) => instance.get_property(name),
+            _ => {
+                self.runtime_error("Only instances and modules have properties.");
+                return None;
+            }
+        };
+        if value.is_none() {
+            match receiver.as_obj() {
+                Some(Obj::module(module)) => self.runtime_error(&format!(
+                    "Module '{}' does not export '{}'.",
+                    module.name, name
+                )),
+                _ => self.runtime_error(&format!("Undefined property '{}'.", name)),
+            }
+        }
+        return value;
+    }
+
+    /// `receiver.name(args)`, host methods run directly, anything else is looked up and called
+    fn invoke(&mut self, name: &str, arg_count: usize, keywords: &[String]) -> bool {
+        let receiver = self.peek(arg_count).clone();
+        if let Some(Obj::instance(instance)) = receiver.as_obj() {
+            if !keywords.is_empty() {
+                self.runtime_error(&format!("{}() takes no keyword arguments.", name));
+                return false;
+            }
+            let first = self.stack.len() - arg_count;
+            let result = instance
+                .invoke(name, &self.stack[first..])
+                .unwrap_or_else(|| Err(format!("Undefined method '{}'.", name)));
+            return match result {
+                Ok(value) => {
+                    self.stack.truncate(first - 1);
+                    self.push(value);
+                    true
+                }
+                Err(message) => {
+                    self.runtime_error(&message);
+                    false
+                }
+            };
+        }
+        let Some(callee) = self.property(&receiver, name) else {
+            return false;
+        };
+        let receiver_slot = self.stack.len() - arg_count - 1;
+        self.stack[receiver_slot] = callee.clone();
+        return self.call_value(callee, arg_count, keywords);
+    }
+
+    fn call_closure(
+        &mut self,
+        closure: Rc<Closure>,
+        arg_count: usize,
+        keywords: &[String],
+    ) -> bool {
+        let function = closure.function.clone();
+        let exact = keywords.is_empty() && !function.is_variadic && arg_count == function.arity;
+        if !exact && !self.bind_arguments(&function, arg_count, keywords) {
+            return false;
+        }
+        let arg_count = function.arity + function.is_variadic as usize;
+        if self.frames.len() >= self.max_frames || self.stack.len() > self.max_stack {
+            self.runtime_error("Stack overflow.");
+            return false;
+        }
+        self.frames.push(CallFrame {
+            closure,
+            ip_index: 0,
+            slots: self.stack.len() - arg_count - 1,
+            is_module: false,
+        });
+        return true;
+    }
+
+    /// rearrange the arguments on the stack into one value per parameter: positional ones
+    /// first, then keyword ones by name, then defaults, with extra positional arguments
+    /// collected into the rest parameter
+    fn bind_arguments(
+        &mut self,
+        function: &Function,
+        arg_count: usize,
+        keywords: &[String],
+    ) -> bool {
+        let positional = arg_count - keywords.len();
+        let required = function.required();
+        if keywords.is_empty() && positional < required
+            || positional > function.arity && !function.is_variadic
+        {
+            let expected = if required == function.arity && !function.is_variadic {
+                format!("{}", required)
+            } else if positional < required {
+                format!("at least {}", required)
+            } else {
+                format!("at most {}", function.arity)
+            };
+            self.runtime_error(&format!(
+                "Expected {} arguments but got {}.",
+                expected, positional
+            ));
+            return false;
+        }
+
+        let args = self.stack.split_off(self.stack.len() - arg_count);
+        let mut params: Vec<Option<Value>> = vec![None; function.arity];
+        for (param, arg) in params.iter_mut().zip(&args[..positional]) {
+            *param = Some(arg.clone());
+        }
+        for (name, arg) in keywords.iter().zip(&args[positional..]) {
+            let Some(index) = function.params.iter().position(|param| param == name) else {
+                self.runtime_error(&format!(
+                    "{} got an unexpected keyword argument '{}'.",
+                    function, name
+                ));
+                return false;
+            };
+            if params[index].is_some() {
+                self.runtime_error(&format!(
+                    "{} got multiple values for argument '{}'.",
+                    function, name
+                ));
+                return false;
+            }
+            params[index] = Some(arg.clone());
+        }
+
+        for (index, param) in params.into_iter().enumerate() {
+            let value = match param {
+                Some(value) => value,
+                None if index >= required => function.defaults[index - required].clone(),
+                None => {
+                    self.runtime_error(&format!(
+                        "{} missing argument '{}'.",
+                        function, function.params[index]
+                    ));
+                    return false;
+                }
+            };
+            self.push(value);
+        }
+        if function.is_variadic {
+            let rest = args[function.arity.min(positional)..positional].to_vec();
+            self.push(Value::list_val(rest));
+        }
+        return true;
+    }
+
+    /// reuse the open upvalue for `slot` if a closure already captured it
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        let position = self
+            .open_upvalues
+            .iter()
+            .position(|upvalue| matches!(*upvalue.borrow(), Upvalue::open(index) if index >= slot));
+        if let Some(position) = position {
+            let existing = &self.open_upvalues[position];
+            if matches!(*existing.borrow(), Upvalue::open(index) if index == slot) {
+                return existing.clone();
+            }
+        }
+        let created = Rc::new(RefCell::new(Upvalue::open(slot)));
+        let position = position.unwrap_or(self.open_upvalues.len());
+        self.open_upvalues.insert(position, created.clone());
+        created
+    }
+
+    /// move every captured variable at or above `last` off the stack
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let Upvalue::open(index) = *upvalue.borrow() else {
+                unreachable!("closed upvalue in the open list");
+            };
+            if index < last {
+                break;
+            }
+            *upvalue.borrow_mut() = Upvalue::closed(self.stack[index].clone());
+            self.open_upvalues.pop();
+        }
     }
 
     fn reset_stack(&mut self) {
-        self.stack_top = 0;
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+        self.loading.clear();
+    }
+
+    /// write compiler diagnostics to the error sink, a failing sink is ignored like a closed stderr
+    fn report(&mut self, diagnostics: &[String]) {
+        for diagnostic in diagnostics {
+            let _ = writeln!(self.err, "{}", diagnostic);
+        }
     }
 
     fn runtime_error(&mut self, msg: &str) {
-        eprint!("\n");
-        // size_t instruction = vm.ip - vm.chunk->code - 1; TODO
-        let instruction = self.ip() as usize - (self.chunk.codes.len() - 1); // self.chunk->code - 1;
-        let line = self.chunk.lines[instruction];
-        eprint!("[line {}] in script\n", line);
+        let mut trace = vec![];
+        for frame in self.frames.iter().rev() {
+            let function = &frame.closure.function;
+            let instruction = frame.ip_index - 1;
+            let line = function.chunk.get_line(instruction);
+            trace.push(match &function.name {
+                Some(name) => format!("[line {}] in {}()", line, name),
+                None if Rc::ptr_eq(&frame.closure.module, &self.main) => {
+                    format!("[line {}] in script", line)
+                }
+                None => format!("[line {}] in {}", line, frame.closure.module),
+            });
+        }
+        let _ = writeln!(self.err, "{}", msg);
+        for line in &trace {
+            let _ = writeln!(self.err, "{}", line);
+        }
+        self.error = Some(LoxError::runtime {
+            message: msg.to_string(),
+            trace,
+        });
         self.reset_stack();
     }
 
     fn push(&mut self, value: Value) {
-        self.stack[self.stack_top] = value;
-        self.stack_top += 1;
+        self.stack.push(value);
     }
 
     fn pop(&mut self) -> Value {
-        self.stack_top -= 1;
-        self.stack[self.stack_top].clone()
+        self.stack.pop().expect("stack underflow")
+    }
+    fn peek(&self, distance: usize) -> &Value {
+        return &self.stack[self.stack.len() - 1 - distance];
+    }
+    /// the operands of a binary operator, left and right, where they are on the stack
+    fn operands(&self) -> (&Value, &Value) {
+        let top = self.stack.len();
+        return (&self.stack[top - 2], &self.stack[top - 1]);
     }
-    fn peek(&self, distance: usize) -> Value {
-        return self.stack[self.stack_top - distance].clone();
+    /// overwrite the top of the stack, for unary operators
+    fn replace_top(&mut self, value: Value) {
+        *self.stack.last_mut().expect("stack underflow") = value;
+    }
+    /// drop the right operand and overwrite the left one with the result
+    fn replace_operands(&mut self, value: Value) {
+        self.stack.pop();
+        self.replace_top(value);
+    }
+    /// integer division and modulo by zero is an error, unlike their float counterparts
+    fn check_int_divisor(&mut self) -> bool {
+        if self.peek(0).is_int() && self.peek(1).is_int() && self.peek(0).as_int() == 0 {
+            self.runtime_error("Division by zero.");
+            return false;
+        }
+        return true;
     }
-    fn is_falsey(&self, value: Value) -> bool {
+
+    fn is_falsey(&self, value: &Value) -> bool {
         value.is_nil() || (value.is_bool() && !value.as_bool())
     }
 
     fn concatenate(&mut self) {
-        let b = self.pop();
-        let a = self.pop();
-        self.push(Value::string_val(format!(
-            "{}{}",
-            a.as_string(),
-            b.as_string()
-        )));
+        let (a, b) = self.operands();
+        let value = Value::string_val(format!("{}{}", a.as_string(), b.as_string()));
+        self.replace_operands(value);
+    }
+
+    fn read_string(&mut self, instruction: OpCode) -> String {
+        self.read_constant(instruction).as_string().into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Counter;
+
+    impl LoxClass for Counter {
+        const NAME: &'static str = "Counter";
+
+        fn construct(_args: &[Value]) -> Result<Self, String> {
+            Ok(Counter)
+        }
+    }
+
+    fn quiet_vm() -> VM {
+        VM::new(VMConfig {
+            out: Box::new(std::io::sink()),
+            err: Box::new(std::io::sink()),
+            ..VMConfig::default()
+        })
+    }
+
+    #[test]
+    fn host_bindings_survive_running_a_file() {
+        let mut vm = quiet_vm();
+        vm.register_class::<Counter>();
+        vm.set_global("limit", 3).unwrap();
+        let source = "var counter = Counter(); var result = limit + 1;";
+        assert_eq!(
+            vm.interpret_file(source, Path::new("main.lox")),
+            INTERPRET_OK
+        );
+        assert_eq!(vm.get_global("result"), Some(Value::int_val(4)));
     }
 
-    fn read_string(&mut self) -> String {
-        self.read_constant().as_string().into()
+    #[test]
+    fn imported_modules_see_host_classes() {
+        let dir = std::env::temp_dir().join(format!("rlox-host-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("counters.lox"),
+            "export fun make() { return Counter(); }",
+        )
+        .unwrap();
+        let mut vm = quiet_vm();
+        vm.register_class::<Counter>();
+        let source = "import \"counters.lox\" as m; var result = m.make();";
+        let result = vm.interpret_file(source, &dir.join("main.lox"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(result, INTERPRET_OK);
+        let result = vm.get_global("result").unwrap();
+        assert!(matches!(result.as_obj(), Some(Obj::instance(i)) if i.class_name == "Counter"));
     }
 }